@@ -0,0 +1,179 @@
+// FRAMのイメージからログを取り出すホスト用のツール
+//
+// fram-dump [オプション] <イメージのファイル(-なら標準入力)>
+//
+// FramLog::dump_rawの出力をシリアルから取り込んだテキストも、イメージに戻して読む
+//
+// cargo run --bin fram-dump --features host-tools --target x86_64-unknown-linux-gnu -- --format csv fram.bin
+
+use anyhow::{anyhow, bail, Context};
+use fram::fram_logger::{ExportFilter, ExportFormat, Image, RawDecoder, TimeBound};
+use std::io::{self, Read, Write};
+
+const USAGE: &str = "\
+usage: fram-dump [options] <image>
+
+Decode a raw FRAM image ('-' for stdin) into log records, oldest first.
+The image may also be a serial capture of FramLog::dump_raw output.
+
+options:
+  -f, --format <text|json|csv>  output format (default: text)
+  -l, --level <level>           most verbose level to show (e.g. warn)
+  -b, --boot <boots>            boots to show: N, N..M, N.., ..M or last
+      --from <time>             show records at or after time
+      --to <time>               show records at or before time
+  -e, --elf <file>              firmware ELF with flog! format strings (repeatable)
+  -o, --save-image <file>       save the decoded image (e.g. from a serial capture)
+  -h, --help                    show this help
+
+<time> is ms since boot (e.g. 1500) or UTC date and time (e.g. 2024-05-01T12:34:56Z).";
+
+struct Args {
+    image: String,
+    format: ExportFormat,
+    filter: ExportFilter,
+    boot: Option<String>,
+    elf: Vec<String>,
+    save_image: Option<String>,
+}
+
+fn parse_args() -> anyhow::Result<Option<Args>> {
+    let mut args = Args {
+        image: String::new(),
+        format: ExportFormat::Text,
+        filter: ExportFilter::new(),
+        boot: None,
+        elf: Vec::new(),
+        save_image: None,
+    };
+    let mut image = None;
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .ok_or_else(|| anyhow!("missing value for '{}'", arg))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-f" | "--format" => args.format = value()?.parse()?,
+            "-l" | "--level" => {
+                let level = value()?;
+                args.filter.level = level
+                    .parse()
+                    .map_err(|_| anyhow!("invalid level '{}'", level))?;
+            }
+            "-b" | "--boot" => args.boot = Some(value()?),
+            "--from" => args.filter.from = Some(value()?.parse::<TimeBound>()?),
+            "--to" => args.filter.to = Some(value()?.parse::<TimeBound>()?),
+            "-e" | "--elf" => args.elf.push(value()?),
+            "-o" | "--save-image" => args.save_image = Some(value()?),
+            _ if arg.starts_with('-') && arg != "-" => bail!("unknown option '{}'", arg),
+            _ if image.is_none() => image = Some(arg),
+            _ => bail!("more than one image given"),
+        }
+    }
+    args.image = image.ok_or_else(|| anyhow!("no image given"))?;
+    Ok(Some(args))
+}
+
+// "3"、"3..5"、"3.."、"..5"、"last"
+fn parse_boots(s: &str, last: u32) -> anyhow::Result<std::ops::RangeInclusive<u32>> {
+    let error = || anyhow!("invalid boot '{}'", s);
+    let boot = |v: &str, default: u32| match v {
+        "" => Ok(default),
+        "last" => Ok(last),
+        v => v.parse().map_err(|_| error()),
+    };
+    match s.split_once("..") {
+        Some((from, to)) => Ok(boot(from, 0)?..=boot(to, u32::MAX)?),
+        None if s.is_empty() => Err(error()),
+        None => {
+            let boot = boot(s, 0)?;
+            Ok(boot..=boot)
+        }
+    }
+}
+
+fn read_file(path: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    if path == "-" {
+        io::stdin().read_to_end(&mut bytes)?;
+    } else {
+        bytes = std::fs::read(path).with_context(|| format!("cannot read '{}'", path))?;
+    }
+    Ok(bytes)
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let mut bytes = read_file(&args.image)?;
+    if RawDecoder::is_capture(&bytes) {
+        bytes = RawDecoder::decode(&String::from_utf8_lossy(&bytes))?;
+    }
+    if let Some(path) = &args.save_image {
+        std::fs::write(path, &bytes).with_context(|| format!("cannot write '{}'", path))?;
+    }
+
+    let image = Image::read(&bytes)?;
+    if image.salvaged {
+        eprintln!("warning: log header is not valid, records were salvaged by sequence number");
+    }
+    if image.corrupt > 0 {
+        eprintln!("warning: {} corrupt record(s) skipped", image.corrupt);
+    }
+
+    let mut strings = image.strings();
+    for path in &args.elf {
+        strings.add_formats(&read_file(path)?);
+    }
+
+    let mut filter = args.filter;
+    if let Some(boot) = &args.boot {
+        filter.boots = Some(parse_boots(boot, image.last_boot().unwrap_or(0))?);
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    args.format
+        .write(&mut out, &image.records, &strings, &filter)?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_boots() {
+        let table = [
+            ("3", Some(3..=3)),
+            ("3..5", Some(3..=5)),
+            ("3..", Some(3..=u32::MAX)),
+            ("..5", Some(0..=5)),
+            ("..", Some(0..=u32::MAX)),
+            ("last", Some(9..=9)),
+            ("last..", Some(9..=u32::MAX)),
+            ("2..last", Some(2..=9)),
+            ("", None),
+            ("x", None),
+            ("-1", None),
+            ("3..x", None),
+            ("3...5", None),
+        ];
+        for (s, expected) in table {
+            assert_eq!(parse_boots(s, 9).ok(), expected, "{:?}", s);
+        }
+    }
+}
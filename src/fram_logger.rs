@@ -1,89 +1,286 @@
-use anyhow::Ok;
-use esp_idf_hal::delay::BLOCK;
-use esp_idf_hal::gpio::AnyIOPin;
-use esp_idf_hal::i2c::{I2c, I2cConfig, I2cDriver};
-use esp_idf_hal::peripheral::Peripheral;
-use esp_idf_hal::prelude::*;
-use esp_idf_hal::units::Hertz;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard, OnceLock, TryLockError};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
-const I2C_ADDRESS: u8 = 0x50;
+mod clock;
+mod config;
+mod crc;
+pub mod deferred;
+mod device;
+mod device_id;
+mod error;
+#[cfg(target_os = "espidf")]
+mod esp;
+mod export;
+mod fields;
+mod filter;
+mod header;
+mod intern;
+mod part;
+mod raw;
+mod record;
+mod reset;
+mod ring;
+mod selftest;
+mod sink;
+pub use self::clock::{Clock, FakeClock, ParseWallTimeError, StdClock, WallTime};
+pub use self::config::{ConfigError, FramConfig};
+pub use self::deferred::DeferredArg;
+pub use self::device::{FramDevice, I2cFram, MemFram};
+pub use self::device_id::DeviceId;
+pub use self::error::FramError;
+pub use self::export::{ExportFilter, ExportFormat, Image, ParseExportError, TimeBound};
+use self::fields::encode_value;
+pub use self::fields::{Field, FieldValue, JsonFields, JsonStr, KeyValues};
+pub use self::filter::{Filter, ParseFilterError};
+use self::header::{Header, HEADER_SIZE};
+pub use self::intern::StringTable;
+use self::intern::{encode_string, fnv1a};
+pub use self::part::FramPart;
+pub use self::raw::{RawDecoder, RawEncoder, RawError};
+use self::record::{resync, RecordHeader, MAX_EXTENSION_SIZE, RECORD_HEADER_SIZE};
+pub use self::record::{Record, RecordDisplay, RecordKind, Records, SourceLocation};
+pub use self::reset::{ResetReason, ResetReasonSource, SystemResetReason};
+use self::ring::Ring;
+pub use self::selftest::SelfTest;
+pub use self::sink::{ConsoleSink, Entry, Format, FramSink, LocationDetail, MemorySink, Sink};
 
-// I2Cドライバのインスタンス
-static mut I2C: Option<I2cDriver<'static>> = None;
+// FRAMに書き込むロガー本体
+// デバイスと書き込み位置をまとめてMutexで保護する
+pub struct FramLog {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    device: Box<dyn FramDevice + Send>,
+
+    // リングバッファの位置(FRAMに保存して、再起動後も続きから書き込む)
+    header: Header,
+
+    // タイムスタンプに使う時計
+    clock: Box<dyn Clock>,
 
-// FRAMへの書き込み位置
-static mut CURSOR: u16 = 0;
+    // この起動で書き込んだ文字列のハッシュと、そのレコードのシーケンス番号
+    interned: HashMap<u32, u32>,
 
-// I2Cの初期化
-fn i2c_master_init<'d>(
-    i2c: impl Peripheral<P = impl I2c> + 'd,
-    sda: AnyIOPin,
-    scl: AnyIOPin,
-    baudrate: Hertz,
-) -> anyhow::Result<I2cDriver<'d>> {
-    let config = I2cConfig::new().baudrate(baudrate);
-    let driver = I2cDriver::new(i2c, sda, scl, &config)?;
-    Ok(driver)
+    // 最後に捨てたレコードのシーケンス番号
+    dropped: Option<u32>,
+
+    // この起動でflog!に使った書式文字列(show_logで使う)
+    formats: HashMap<u32, &'static str>,
 }
 
-fn write_30_byte(adrs: u16, data: &[u8]) -> anyhow::Result<()> {
-    let mut buffer: [u8; 32] = [0; 32];
+// fprint!やlogから使うインスタンス
+static FRAM_LOG: OnceLock<FramLog> = OnceLock::new();
 
-    // 最初の2byteがアドレスなので、一度に書き込めるデータは30byteまで
-    buffer[0] = (adrs >> 8) as u8;
-    buffer[1] = adrs as u8;
-    buffer[2..2 + data.len()].copy_from_slice(data);
-    unsafe {
-        I2C.as_mut().unwrap().write(I2C_ADDRESS, &buffer, BLOCK)?;
+impl FramLog {
+    pub fn new(device: impl FramDevice + Send + 'static) -> Result<Self, FramError> {
+        Self::with_clock(device, StdClock::new())
     }
-    Ok(())
-}
 
-fn write_fram(adrs: u16, data: &[u8]) -> anyhow::Result<()> {
-    let mut i = 0;
+    // タイムスタンプに使う時計を指定する
+    pub fn with_clock(
+        device: impl FramDevice + Send + 'static,
+        clock: impl Clock + 'static,
+    ) -> Result<Self, FramError> {
+        let inner = Inner::open(Box::new(device), Box::new(clock))?;
+        Ok(FramLog {
+            inner: Mutex::new(inner),
+        })
+    }
 
-    // 30byteずつ書き込む
-    while i < data.len() {
-        let mut j = i + 30;
-        if j > data.len() {
-            j = data.len();
+    // 何回目の起動か(FramLogを作るたびに1増える)
+    pub fn boot_count(&self) -> u32 {
+        self.lock().header.boot
+    }
+
+    // fprint!やlogの書き込み先として登録する
+    pub fn install(self) -> Result<&'static FramLog, FramError> {
+        if FRAM_LOG.set(self).is_err() {
+            return Err(FramError::AlreadyInitialized);
         }
-        write_30_byte(adrs + i as u16, &data[i..j])?;
-        i += 30;
+        fram_log()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // 書き込み中にpanicしても、ログは取り続けたいのでpoisonは無視する
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // ロックを取れなければ一定時間で諦める
+    // ロックを持ったままpanicした場合に、panicハンドラがデッドロックしないようにする
+    fn try_lock(&self) -> Result<MutexGuard<'_, Inner>, FramError> {
+        for _ in 0..100 {
+            match self.inner.try_lock() {
+                Ok(inner) => return Ok(inner),
+                Err(TryLockError::Poisoned(e)) => return Ok(e.into_inner()),
+                Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        Err(FramError::Timeout)
+    }
+
+    // メッセージ全体を1つのレコードとして書き込むので、他のタスクと混ざらない
+    pub fn print(&self, args: fmt::Arguments) -> Result<(), FramError> {
+        let s = fmt::format(args);
+        self.lock()
+            .append(RecordKind::Text, None, None, &[], s.as_bytes())
+    }
+
+    // レベル付きのログを書き込む
+    pub fn log(&self, level: Level, args: fmt::Arguments) -> Result<(), FramError> {
+        self.log_at(level, None, None, None, &[], args)
+    }
+
+    // ログを書き込んだ場所やkey-valueとともに書き込む
+    // ファイル名とモジュール名は、ハッシュにして書き込む
+    pub fn log_at(
+        &self,
+        level: Level,
+        file: Option<&str>,
+        line: Option<u32>,
+        module_path: Option<&str>,
+        fields: &[Field],
+        args: fmt::Arguments,
+    ) -> Result<(), FramError> {
+        let s = fmt::format(args);
+        self.lock()
+            .append_log(level, file, line, module_path, fields, &s)
+    }
+
+    // panicハンドラ用(ロックを取れなければ諦める)
+    fn try_log_at(
+        &self,
+        level: Level,
+        file: Option<&str>,
+        line: Option<u32>,
+        module_path: Option<&str>,
+        fields: &[Field],
+        args: fmt::Arguments,
+    ) -> Result<(), FramError> {
+        let s = fmt::format(args);
+        self.try_lock()?
+            .append_log(level, file, line, module_path, fields, &s)
+    }
+
+    // 書式化前のログを書き込む(flog!から使う)
+    // entryはdeferred::entryで埋め込んだ書式文字列
+    pub fn log_deferred(
+        &self,
+        level: Level,
+        entry: &'static [u8],
+        args: &[FieldValue],
+    ) -> Result<(), FramError> {
+        let id = entry
+            .get(deferred::MARKER.len()..deferred::MARKER.len() + 4)
+            .ok_or(FramError::Corrupt)?;
+        let mut payload = id.to_vec();
+        for arg in args {
+            encode_value(&mut payload, arg);
+        }
+
+        let mut inner = self.lock();
+        let id = u32::from_le_bytes(id.try_into().unwrap());
+        if let std::collections::hash_map::Entry::Vacant(e) = inner.formats.entry(id) {
+            if let Some((_, fmt)) = deferred::parse_entry(entry) {
+                e.insert(fmt);
+            }
+        }
+        inner.append(RecordKind::Deferred, Some(level), None, &[], &payload)
     }
-    Ok(())
-}
 
-pub fn read_fram(adrs: u16, data: &mut [u8]) -> anyhow::Result<()> {
-    let buffer: [u8; 2] = [(adrs >> 8) as u8, adrs as u8];
-    unsafe {
-        // アドレスを書き込んでから読み込む
-        I2C.as_mut().unwrap().write(I2C_ADDRESS, &buffer, BLOCK)?;
-        I2C.as_mut().unwrap().read(I2C_ADDRESS, data, BLOCK)?;
+    pub fn read(&self, adrs: u32, data: &mut [u8]) -> Result<(), FramError> {
+        self.lock().device.read(adrs, data)
+    }
+
+    // 保存されているレコードを、古い順にそのまま読み出す
+    pub fn read_log(&self) -> Result<Vec<u8>, FramError> {
+        self.lock().read_log()
+    }
+
+    // 保存されているレコードを古い順に返す
+    // 壊れたレコードは読み飛ばす
+    pub fn records(&self) -> Result<Vec<Record>, FramError> {
+        let bytes = self.read_log()?;
+        Ok(Records::new(&bytes).filter_map(Result::ok).collect())
+    }
+
+    // 起動の区切りを、リセットの原因とともに書き込む
+    pub fn mark_boot(&self, source: &dyn ResetReasonSource) -> Result<(), FramError> {
+        let reason = source.reset_reason();
+        self.lock()
+            .append(RecordKind::Boot, None, None, &[], &[reason.to_u8()])
+    }
+
+    // FRAMの自己診断(read_idならDevice IDも読む)
+    // ログは書き換えない
+    pub fn self_test(&self, read_id: bool) -> SelfTest {
+        SelfTest::run(&mut *self.lock().device, read_id)
     }
-    Ok(())
 }
 
-pub fn init(peripherals: &mut Peripherals) -> anyhow::Result<()> {
-    unsafe {
-        let i2c = i2c_master_init(
-            peripherals.i2c0.clone_unchecked(),
-            peripherals.pins.gpio18.clone_unchecked().into(),
-            peripherals.pins.gpio17.clone_unchecked().into(),
-            1000.kHz().into(),
-        )?;
-        I2C = Some(i2c);
+// 登録されているインスタンスを返す
+pub fn fram_log() -> Result<&'static FramLog, FramError> {
+    FRAM_LOG.get().ok_or(FramError::NotInitialized)
+}
+
+// 任意のFRAMデバイスで初期化する
+// 起動回数を1増やし、リセットの原因を起動のレコードとして書き込む
+// 書き込めなければ登録しない
+pub fn init_with(device: impl FramDevice + Send + 'static) -> Result<&'static FramLog, FramError> {
+    let log = FramLog::new(device)?;
+    log.mark_boot(&SystemResetReason)?;
+    log.install()
+}
+
+// embedded-halのI2cで初期化する
+// バスを共有する場合は、embedded-hal-busのMutexDeviceなどを渡す
+// config.detect_partなら、FRAMの容量をDevice IDから調べる
+pub fn init_shared(
+    i2c: impl embedded_hal::i2c::I2c + Send + 'static,
+    config: &FramConfig,
+) -> Result<&'static FramLog, FramError> {
+    config.validate()?;
+    let fram = I2cFram::new(i2c, config.fram_part());
+    let fram = if config.detect_part {
+        detect_part(fram, config)?
+    } else {
+        fram
     };
-    Ok(())
+    init_with(fram)
+}
+
+// Device IDに対応していれば、容量を合わせる
+// 対応していない、または知らないIDなら、config.partのまま
+fn detect_part<I: embedded_hal::i2c::I2c>(
+    mut fram: I2cFram<I>,
+    config: &FramConfig,
+) -> Result<I2cFram<I>, FramError> {
+    match fram.device_id()?.and_then(|id| id.part(config.part)) {
+        Some(part) => {
+            let config = config.part(part);
+            config.validate()?;
+            Ok(I2cFram::new(fram.release(), config.fram_part()))
+        }
+        None => Ok(fram),
+    }
 }
 
+#[cfg(target_os = "espidf")]
+pub use self::esp::{i2c_master_init, init, SharedI2c};
+
 // println!やprint!を同じように使えるマクロ
 
-use core::fmt::{self, Write};
+use log::{Level, Metadata};
+use std::fmt;
 
 pub fn fram_print(args: fmt::Arguments) {
-    let mut writer = FramWriter {};
-    writer.write_fmt(args).unwrap();
+    // 初期化前やFRAMが使えないときは何もしない
+    if let Ok(log) = fram_log() {
+        let _ = log.print(args);
+    }
 }
 
 #[macro_export]
@@ -97,81 +294,360 @@ macro_rules! fprintln {
     ($fmt:expr, $($arg:tt)*) => (fprint!(concat!($fmt, "\n"), $($arg)*));
 }
 
-struct FramWriter;
+// 書式化を後回しにしてFRAMに書き込む(deferred.rs)
+// FRAMの出力先のフィルタで、呼び出したモジュール(target)のレベルを判定する
+pub fn fram_log_deferred(level: Level, target: &str, entry: &'static [u8], args: &[FieldValue]) {
+    let metadata = Metadata::builder().level(level).target(target).build();
+    if !deferred_enabled(LOGGER.get(), &metadata) {
+        return;
+    }
+    // 初期化前やFRAMが使えないときは何もしない
+    if let Ok(log) = fram_log() {
+        let _ = log.log_deferred(level, entry, args);
+    }
+}
 
-fn write(s: &str) -> fmt::Result {
-    // 文字列をFRAMに書き込む
-    write_fram(unsafe { CURSOR }, s.as_bytes()).unwrap();
+// ロガーを登録していなければ、既定のフィルタ(Info以上)で判定する
+fn deferred_enabled(logger: Option<&FramLogger>, metadata: &Metadata) -> bool {
+    match logger {
+        Some(logger) => logger.fram_enabled(metadata),
+        None => Filter::default().enabled(metadata),
+    }
+}
 
-    // 書き込んだ分だけカーソルを進める
-    unsafe {
-        CURSOR += s.len() as u16;
-        while CURSOR > 0x2000 {
-            CURSOR -= 0x2000;
+// flog!(log::Level::Info, "rpm = {}", rpm)
+// 引数は整数、浮動小数点数、bool、char、文字列のみ
+// 書式に{rpm}のような名前や、{:.*}のような引数での精度を使うとコンパイルエラーになる
+#[macro_export]
+macro_rules! flog {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        // 書式と引数の数を、コンパイル時に検査するだけ(実行はしない)
+        if false {
+            let _ = format_args!($fmt $(, $arg)*);
         }
+        const FMT: &str = $fmt;
+        static ENTRY: [u8; $crate::fram_logger::deferred::entry_len(FMT)] =
+            $crate::fram_logger::deferred::entry(FMT);
+        $crate::fram_logger::fram_log_deferred(
+            $level,
+            module_path!(),
+            &ENTRY,
+            &[$($crate::fram_logger::DeferredArg::to_value(&$arg)),*],
+        );
+    }};
+}
+
+impl Inner {
+    // ヘッダからリングバッファの位置を復元する
+    // フォーマットされていなければ、空のログを作る
+    // 壊れている場合も、空のログを作り直す
+    // 開くたびに起動回数を1増やす
+    fn open(
+        mut device: Box<dyn FramDevice + Send>,
+        clock: Box<dyn Clock>,
+    ) -> Result<Inner, FramError> {
+        // ヘッダの後ろに、空のレコードが1つも入らないFRAMは使えない
+        let capacity = device.capacity();
+        if capacity <= HEADER_SIZE as usize + RECORD_HEADER_SIZE + MAX_EXTENSION_SIZE {
+            return Err(FramError::OutOfRange {
+                adrs: 0,
+                len: capacity,
+            });
+        }
+
+        // FRAMが応答しなければ、ヘッダを読む前にエラーにする
+        device.probe()?;
+
+        let mut header = match Header::load(&mut *device) {
+            Ok(Some(header)) => header,
+            Ok(None) | Err(FramError::Corrupt) => Header::format(&mut *device)?,
+            Err(e) => return Err(e),
+        };
+        header.boot = header.boot.wrapping_add(1);
+        header.store(&mut *device)?;
+
+        Ok(Inner {
+            device,
+            header,
+            clock,
+            interned: HashMap::new(),
+            dropped: None,
+            formats: HashMap::new(),
+        })
     }
 
-    // 終端文字を書き込む
-    write_fram(unsafe { CURSOR }, b"\0").unwrap();
+    // ヘッダ以降をリングバッファとして使う
+    fn ring(&self) -> Ring {
+        Ring::new(HEADER_SIZE, self.device.capacity() as u32)
+    }
 
-    core::result::Result::Ok(())
-}
+    // 起動からのms
+    fn timestamp(&self) -> u32 {
+        self.clock.uptime().as_millis() as u32
+    }
 
-impl Write for FramWriter {
-    fn write_str(&mut self, s: &str) -> Result<(), std::fmt::Error> {
-        write(&s)
+    // UNIX時間のms(時刻を合わせていなければNone)
+    fn wall_time(&self) -> Option<u64> {
+        let wall_time = self.clock.wall_time()?;
+        let since_epoch = wall_time.duration_since(UNIX_EPOCH).ok()?;
+        Some(since_epoch.as_millis() as u64)
     }
-}
 
-// FRAMに書き込まれたログを表示
-pub fn show_log() {
-    let mut buffer: [u8; 32] = [0; 32];
-    let mut adrs = 0;
-    let mut flag = true;
+    // ログのレコードを書き込む
+    // 場所があれば、先にファイル名とモジュール名の文字列を書き込む
+    fn append_log(
+        &mut self,
+        level: Level,
+        file: Option<&str>,
+        line: Option<u32>,
+        module_path: Option<&str>,
+        fields: &[Field],
+        text: &str,
+    ) -> Result<(), FramError> {
+        let location = match (file, module_path) {
+            (None, None) => None,
+            _ => Some(SourceLocation {
+                file: file.map_or(Ok(0), |file| self.intern(file))?,
+                module: module_path.map_or(Ok(0), |module| self.intern(module))?,
+                line: line.unwrap_or(0),
+            }),
+        };
+        self.append(
+            RecordKind::Log,
+            Some(level),
+            location,
+            fields,
+            text.as_bytes(),
+        )
+    }
+
+    // 文字列のハッシュを返す
+    // この起動でまだ書き込んでいないか、書き込んだレコードが上書きされていれば書き込む
+    fn intern(&mut self, s: &str) -> Result<u32, FramError> {
+        let hash = fnv1a(s);
+        // シーケンス番号は折り返すので、差で比べる
+        let dropped = |seq: u32| {
+            self.dropped
+                .is_some_and(|dropped| (dropped.wrapping_sub(seq) as i32) >= 0)
+        };
+        let written = self.interned.get(&hash).is_some_and(|&seq| !dropped(seq));
+        if !written {
+            let seq = self.header.seq;
+            match self.append(RecordKind::Str, None, None, &[], &encode_string(hash, s)) {
+                Ok(()) => {
+                    self.interned.insert(hash, seq);
+                }
+                // 入りきらない長さの文字列は、不明(0)にする
+                Err(FramError::TooLarge(_)) => return Ok(0),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(hash)
+    }
+
+    // レコードを1つ書き込む
+    // 1. 上書きする古いレコードを捨てて、headを確定する
+    // 2. tailより先にレコードを書き込む(まだ読み出されない)
+    // 3. tailを進めて確定する
+    // どこで電源が切れても、書き込み途中のレコードが読み出されることはない
+    fn append(
+        &mut self,
+        kind: RecordKind,
+        level: Option<Level>,
+        location: Option<SourceLocation>,
+        fields: &[Field],
+        payload: &[u8],
+    ) -> Result<(), FramError> {
+        let ring = self.ring();
+
+        let mut record = Record {
+            kind,
+            level,
+            seq: self.header.seq,
+            boot: self.header.boot,
+            timestamp: self.timestamp(),
+            wall_time: self.wall_time(),
+            location,
+            fields: fields.to_vec(),
+            payload: Vec::new(),
+        };
+
+        // key-valueだけでリングに入りきらなければ、key-valueは捨てる
+        let empty_size = record.encoded_size();
+        if empty_size >= ring.size() || empty_size - RECORD_HEADER_SIZE > Record::MAX_PAYLOAD {
+            record.fields.clear();
+        }
+
+        // リングに入りきらない場合、テキストとログはペイロードの最後の部分だけを残す
+        // 文字列と書式化前のログは先頭のハッシュやIDが欠けると読めないので、書き込まない
+        // (満杯でもtailはheadの1byte手前までしか進めない)
+        let overhead = record.encoded_size();
+        let max = (ring.size() - 1)
+            .min(Record::MAX_PAYLOAD + RECORD_HEADER_SIZE)
+            .checked_sub(overhead)
+            .ok_or(FramError::TooLarge(overhead + payload.len()))?;
+        let mut payload = payload;
+        if payload.len() > max {
+            match kind {
+                RecordKind::Text | RecordKind::Log => payload = &payload[payload.len() - max..],
+                _ => return Err(FramError::TooLarge(payload.len())),
+            }
+        }
+        record.payload = payload.to_vec();
+        let bytes = record.encode();
+        self.make_room(bytes.len())?;
+
+        // レコードをFRAMに書き込み、書き込んだ分だけtailを進める
+        let tail = self.header.tail;
+        ring.write(&mut *self.device, tail, &bytes)?;
+        self.header.tail = ring.advance(tail, bytes.len());
+        self.header.seq = self.header.seq.wrapping_add(1);
 
-    println!("\n\nLog - - - - - - - - - - - - - - -");
+        // 位置を保存して、レコードを確定する
+        self.header.store(&mut *self.device)
+    }
+
+    // 空きがlen byteになるまで、古いレコードから捨ててheadを進める
+    // 上書きを始める前に、新しいheadを確定しておく
+    fn make_room(&mut self, len: usize) -> Result<(), FramError> {
+        let ring = self.ring();
+        let max = ring.size() - 1;
+        let old_head = self.header.head;
 
-    while flag {
-        let mut size = 0;
-        read_fram(adrs, &mut buffer).unwrap();
-        adrs += buffer.len() as u16;
-        for b in buffer {
-            size += 1;
-            if b == 0 {
-                flag = false;
+        loop {
+            let Header { head, tail, .. } = self.header;
+            let used = ring.distance(head, tail);
+            if max - used >= len {
                 break;
             }
+
+            let mut bytes = [0; RECORD_HEADER_SIZE];
+            ring.read(&mut *self.device, head, &mut bytes)?;
+            let size = match RecordHeader::decode(&bytes) {
+                Ok(record) if record.record_size() <= used => {
+                    self.dropped = Some(record.seq);
+                    record.record_size()
+                }
+                // 壊れていれば、次の正しいレコードまで捨てる
+                // どの文字列が残っているか分からないので、すべて書き直す
+                _ => {
+                    self.interned.clear();
+                    resync(&self.read_log()?)
+                }
+            };
+            self.header.head = ring.advance(head, size);
+            self.header.wrapped = true;
         }
-        let s = std::str::from_utf8(&buffer[0..size]).unwrap();
-        print!("{}", s);
+
+        if self.header.head != old_head {
+            self.header.store(&mut *self.device)?;
+        }
+        Ok(())
     }
 
-    println!("- - - - - - - - - - - - - - - - -");
+    fn read_log(&mut self) -> Result<Vec<u8>, FramError> {
+        let ring = self.ring();
+        let Header { head, tail, .. } = self.header;
+
+        let mut log = vec![0; ring.distance(head, tail)];
+        ring.read(&mut *self.device, head, &mut log)?;
+        Ok(log)
+    }
 }
 
+impl FramLog {
+    // FRAMに書き込まれたログを表示
+    pub fn show_log(&self) -> Result<(), FramError> {
+        self.write_log(&mut io::stdout().lock())
+    }
+
+    // 表示はfram-dumpのテキストと同じ(export.rs)
+    fn write_log(&self, w: &mut dyn io::Write) -> Result<(), FramError> {
+        let log = self.read_log()?;
+        let mut records = Vec::new();
+        let mut corrupt = 0;
+        for record in Records::new(&log) {
+            match record {
+                Ok(record) => records.push(record),
+                Err(_) => corrupt += 1,
+            }
+        }
+        let mut strings = StringTable::from_records(&records);
+        for (&id, fmt) in &self.lock().formats {
+            strings.insert(id, fmt);
+        }
+
+        // 表示できなくても、ログには影響しないので無視する
+        let _ = write_text(w, &records, corrupt, &strings);
+        Ok(())
+    }
+
+    // FRAMの中身をすべて、行ごとにCRCを付けて出力する(raw.rs)
+    // 読み出しながら出力するので、FRAMの大きさのバッファはいらない
+    // 途中で書き込まれないように、出力が終わるまでロックを持つ(その間ログの書き込みは待つ)
+    pub fn dump_raw(&self) -> Result<(), FramError> {
+        self.write_raw(|line| println!("{}", line))
+    }
+
+    fn write_raw(&self, emit: impl FnMut(&str)) -> Result<(), FramError> {
+        let mut inner = self.lock();
+        let size = inner.device.capacity();
+        RawEncoder::stream(size, |adrs, data| inner.device.read(adrs, data), emit)
+    }
+}
+
+// show_logの表示(壊れたレコードは数だけ表示する)
+fn write_text(
+    w: &mut dyn io::Write,
+    records: &[Record],
+    corrupt: usize,
+    strings: &StringTable,
+) -> io::Result<()> {
+    writeln!(w, "\n\nLog - - - - - - - - - - - - - - -")?;
+    ExportFormat::Text.write(w, records, strings, &ExportFilter::new())?;
+    if corrupt > 0 {
+        writeln!(w, "({} corrupt record(s) skipped)", corrupt)?;
+    }
+    writeln!(w, "- - - - - - - - - - - - - - - - -")
+}
+
+// 登録されているロガー(panicハンドラも同じ出力先に書き込む)
+static LOGGER: OnceLock<FramLogger> = OnceLock::new();
+
 // FRAMを使ったpanicハンドラ
 use std::panic::{self, PanicInfo};
 
 fn fram_panic_handler(info: &PanicInfo) {
-    if let Some(location) = info.location() {
-        fprintln!(
-            "Panic occurred in file '{}' at line {}",
-            location.file(),
-            location.line()
-        );
-        println!(
-            "Panic occurred in file '{}' at line {}",
-            location.file(),
-            location.line()
+    // ロガーが登録されていなければ、FRAMとシリアルに出力する
+    let default;
+    let logger = match LOGGER.get() {
+        Some(logger) => logger,
+        None => {
+            default = FramLogger::default();
+            &default
+        }
+    };
+
+    let location = info.location();
+    if let Some(location) = location {
+        logger.write_panic(
+            Some(location),
+            format_args!(
+                "Panic occurred in file '{}' at line {}",
+                location.file(),
+                location.line()
+            ),
         );
     } else {
-        fprintln!("Panic occurred but can't get location information...");
-        println!("Panic occurred but can't get location information...");
+        logger.write_panic(
+            None,
+            format_args!("Panic occurred but can't get location information..."),
+        );
     }
-    fprintln!("{}", info);
-    println!("{}", info);
+    logger.write_panic(location, format_args!("{}", info));
 
-    // panicが発生したら再起動せずに停止
+    // panicが発生したら再起動せずに停止(ホストではそのまま戻る)
+    #[cfg(target_os = "espidf")]
     loop {
         // ウォッチドッグタイマによるリセットを防ぐ
         esp_idf_hal::delay::FreeRtos::delay_ms(1000);
@@ -182,26 +658,702 @@ pub fn set_panic_handler() {
     panic::set_hook(Box::new(fram_panic_handler));
 }
 
-use log::{Level, Metadata, Record};
-pub struct FramLogger;
+// 登録された出力先のうち、フィルタを通ったものすべてにログを書き込む
+pub struct FramLogger {
+    sinks: Vec<(Filter, Box<dyn Sink>)>,
+}
+
+// FRAMとシリアルにInfo以上を出力する
+impl Default for FramLogger {
+    fn default() -> Self {
+        FramLogger::new()
+            .sink(Filter::default(), FramSink::new(Format::Message))
+            .sink(Filter::default(), ConsoleSink::new(Format::Level))
+    }
+}
+
+impl FramLogger {
+    // 出力先のないロガー
+    pub fn new() -> Self {
+        FramLogger { sinks: Vec::new() }
+    }
+
+    // 出力先を追加する
+    pub fn sink(mut self, filter: Filter, sink: impl Sink + 'static) -> Self {
+        self.sinks.push((filter, Box::new(sink)));
+        self
+    }
+
+    // どれかの出力先で通す最も詳しいレベル
+    pub fn max_level(&self) -> log::LevelFilter {
+        self.sinks
+            .iter()
+            .map(|(filter, _)| filter.max_level())
+            .fold(log::LevelFilter::Off, Ord::max)
+    }
+
+    // FRAMに書き込む出力先のどれかで通すか
+    fn fram_enabled(&self, metadata: &Metadata) -> bool {
+        self.sinks
+            .iter()
+            .any(|(filter, sink)| sink.writes_fram() && filter.enabled(metadata))
+    }
+
+    fn write_panic(&self, location: Option<&panic::Location>, args: fmt::Arguments) {
+        let entry = Entry {
+            level: Level::Error,
+            target: "panic",
+            args,
+            file: location.map(|location| location.file()),
+            line: location.map(|location| location.line()),
+            module_path: None,
+            fields: Vec::new(),
+        };
+        for (filter, sink) in &self.sinks {
+            if filter.enabled(&entry.metadata()) {
+                sink.write_panic(&entry);
+            }
+        }
+    }
+}
 
 impl log::Log for FramLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        self.sinks
+            .iter()
+            .any(|(filter, _)| filter.enabled(metadata))
     }
 
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            fprintln!("{} - {}", record.level(), record.args());
-            println!("{} - {}", record.level(), record.args());
+    fn log(&self, record: &log::Record) {
+        let entry = Entry::from_record(record);
+        for (filter, sink) in &self.sinks {
+            if filter.enabled(record.metadata()) {
+                sink.write(&entry);
+            }
         }
     }
 
     fn flush(&self) {}
 }
 
-pub fn set_log(log_level: log::LevelFilter) {
-    log::set_boxed_logger(Box::new(FramLogger))
-        .map(|()| log::set_max_level(log_level))
+// ロガーを登録する
+pub fn set_logger(logger: FramLogger) -> Result<(), log::SetLoggerError> {
+    let max_level = logger.max_level();
+    let logger = LOGGER.get_or_init(|| logger);
+    log::set_logger(logger).map(|()| log::set_max_level(max_level))
+}
+
+// FRAMとシリアルに同じレベルで出力する
+// FRAMが初期化されていなければ、シリアルにだけ出力する
+pub fn set_log(log_level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+    set_log_filters(Filter::new(log_level), Filter::new(log_level))
+}
+
+// FRAMとシリアルに別々のフィルタを指定する
+//
+// set_log_filters("warn".parse()?, "debug,my_app::ble=info".parse()?)
+pub fn set_log_filters(fram: Filter, console: Filter) -> Result<(), log::SetLoggerError> {
+    set_logger(
+        FramLogger::new()
+            .sink(fram, FramSink::new(Format::Message))
+            .sink(console, ConsoleSink::new(Format::Level)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::LevelFilter;
+
+    // 書き込んだFRAMの中身
+    fn image(log: &FramLog) -> Vec<u8> {
+        let mut inner = log.lock();
+        let mut image = vec![0; inner.device.capacity()];
+        inner.device.read(0, &mut image).unwrap();
+        image
+    }
+
+    // 同じ中身のFRAMで開き直す(再起動)
+    fn reopen(log: &FramLog) -> FramLog {
+        FramLog::new(MemFram::from_bytes(image(log))).unwrap()
+    }
+
+    fn texts(log: &FramLog) -> Vec<String> {
+        log.records()
+            .unwrap()
+            .iter()
+            .filter(|record| matches!(record.kind, RecordKind::Log | RecordKind::Text))
+            .map(|record| record.text().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn continues_after_reopen() {
+        let log = FramLog::new(MemFram::new(0x2000)).unwrap();
+        assert_eq!(log.boot_count(), 1);
+        log.log(Level::Info, format_args!("first")).unwrap();
+        log.print(format_args!("second")).unwrap();
+
+        let log = reopen(&log);
+        assert_eq!(log.boot_count(), 2);
+        log.log(Level::Warn, format_args!("third")).unwrap();
+        assert_eq!(texts(&log), ["first", "second", "third"]);
+
+        let records = log.records().unwrap();
+        let seqs: Vec<u32> = records.iter().map(|record| record.seq).collect();
+        assert_eq!(seqs, [0, 1, 2]);
+        assert_eq!(records[2].boot, 2);
+    }
+
+    #[test]
+    fn logs_from_many_threads() {
+        const THREADS: usize = 8;
+        const MESSAGES: usize = 50;
+
+        let log = FramLog::new(MemFram::new(0x10000)).unwrap();
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let log = &log;
+                s.spawn(move || {
+                    for i in 0..MESSAGES {
+                        log.log(Level::Info, format_args!("thread {} message {}", t, i))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        // 混ざったり抜けたりせず、スレッドごとには書き込んだ順に並ぶ
+        let records = log.records().unwrap();
+        assert_eq!(records.len(), THREADS * MESSAGES);
+        let mut next = [0; THREADS];
+        for (seq, record) in records.iter().enumerate() {
+            assert_eq!(record.seq, seq as u32);
+            let text = record.text();
+            let (t, i) = text
+                .strip_prefix("thread ")
+                .and_then(|rest| rest.split_once(" message "))
+                .unwrap();
+            let (t, i): (usize, usize) = (t.parse().unwrap(), i.parse().unwrap());
+            assert_eq!(i, next[t]);
+            next[t] += 1;
+        }
+        assert_eq!(next, [MESSAGES; THREADS]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let log = FramLog::new(MemFram::new(0x200)).unwrap();
+        for i in 0..100 {
+            log.log(Level::Info, format_args!("message {}", i)).unwrap();
+        }
+        assert!(log.lock().header.wrapped);
+
+        // 新しいものだけが、欠けずに順に残る
+        let texts = texts(&reopen(&log));
+        assert!(texts.len() > 1 && texts.len() < 100);
+        let first = 100 - texts.len();
+        for (i, text) in texts.iter().enumerate() {
+            assert_eq!(*text, format!("message {}", first + i));
+        }
+    }
+
+    #[test]
+    fn returns_oldest_first_after_wraps() {
+        let log = FramLog::new(MemFram::new(0x200)).unwrap();
+        let mut written = 0;
+        let mut wraps = 0;
+        for i in 0..200 {
+            let tail = log.lock().header.tail;
+            // 長さを変えて、終端をまたぐ位置をずらす
+            let text = format!("message {} {}", i, "x".repeat(i % 23));
+            log.log(Level::Info, format_args!("{}", text)).unwrap();
+            written += 1;
+            if log.lock().header.tail < tail {
+                wraps += 1;
+            }
+
+            let records = log.records().unwrap();
+            assert!(!records.is_empty());
+            for pair in records.windows(2) {
+                assert_eq!(pair[1].seq, pair[0].seq + 1);
+            }
+            assert_eq!(records.last().unwrap().seq, written - 1);
+            assert_eq!(records.last().unwrap().text(), text);
+        }
+        assert!(wraps >= 3);
+    }
+
+    #[test]
+    fn rewrites_dropped_strings() {
+        let log = FramLog::new(MemFram::new(0x200)).unwrap();
+        let log_motor = |message: &str| {
+            log.log_at(
+                Level::Info,
+                Some("src/motor.rs"),
+                Some(42),
+                Some("app::motor"),
+                &[],
+                format_args!("{}", message),
+            )
+            .unwrap();
+        };
+        let has_strings = || {
+            log.records()
+                .unwrap()
+                .iter()
+                .any(|record| record.kind == RecordKind::Str)
+        };
+
+        log_motor("first");
+        assert!(has_strings());
+
+        // 文字列のレコードが上書きされるまで書き込む
+        let mut i = 0;
+        while has_strings() {
+            log.log(Level::Info, format_args!("message {}", i)).unwrap();
+            i += 1;
+        }
+        assert!(log.lock().header.wrapped);
+
+        // 同じファイルから書き込むと、文字列をもう一度書き込む
+        log_motor("second");
+        let records = log.records().unwrap();
+        let strings = StringTable::from_records(&records);
+        let record = records.last().unwrap();
+        assert_eq!(record.text(), "second");
+        let location = record.location.unwrap();
+        assert_eq!(strings.name(location.file), "src/motor.rs");
+        assert_eq!(strings.name(location.module), "app::motor");
+        assert_eq!(location.line, 42);
+    }
+
+    #[test]
+    fn writes_record_ending_at_capacity() {
+        // 容量ちょうどで終わるレコード(0x2000のFRAMで最後の1byteを書けなかった)
+        let log = FramLog::new(MemFram::new(0x2000)).unwrap();
+        let tail = |log: &FramLog| log.lock().header.tail as usize;
+        let before = tail(&log);
+        log.print(format_args!("x")).unwrap();
+        let overhead = tail(&log) - before - 1;
+
+        while 0x2000 - tail(&log) > 100 {
+            log.print(format_args!("{}", "y".repeat(40))).unwrap();
+        }
+        let last = "z".repeat(0x2000 - tail(&log) - overhead);
+        log.print(format_args!("{}", last)).unwrap();
+        assert_eq!(tail(&log), HEADER_SIZE as usize);
+        assert_eq!(image(&log)[0x2000 - last.len()..], *last.as_bytes());
+
+        let log = reopen(&log);
+        log.print(format_args!("after")).unwrap();
+        assert_eq!(texts(&log).last().unwrap(), "after");
+        assert_eq!(texts(&log)[texts(&log).len() - 2], last);
+    }
+
+    #[test]
+    fn detects_part() {
+        use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+        use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+        let config = FramConfig::new().address(0x52).detect_part(true);
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let table = [
+            // MB85RC256
+            (Ok([0x00, 0xA5, 0x10]), FramPart::MB85RC256),
+            // 知らないメーカー
+            (Ok([0x12, 0x35, 0x10]), FramPart::MB85RC64),
+            // 知らない容量
+            (Ok([0x00, 0xA9, 0x10]), FramPart::MB85RC64),
+            // Device IDに対応していない
+            (Err(nack), FramPart::MB85RC64),
+        ];
+        for (response, part) in table {
+            let transaction = match response {
+                Ok(bytes) => Transaction::write_read(0x7C, vec![0x52 << 1], bytes.to_vec()),
+                Err(e) => Transaction::write_read(0x7C, vec![0x52 << 1], vec![0; 3]).with_error(e),
+            };
+            let fram = I2cFram::new(Mock::new(&[transaction]), config.fram_part());
+            let fram = detect_part(fram, &config).unwrap();
+            assert_eq!(fram.part(), part.i2c_address(0x52));
+            fram.release().done();
+        }
+    }
+
+    #[test]
+    fn truncates_only_text() {
+        let log = FramLog::new(MemFram::new(0x200)).unwrap();
+        let long = format!("head{}tail", "-".repeat(1000));
+        log.print(format_args!("{}", long)).unwrap();
+        log.log(Level::Info, format_args!("{}", long)).unwrap();
+        for record in log.records().unwrap() {
+            assert!(long.ends_with(&*record.text()));
+            assert!(record.text().ends_with("tail"));
+        }
+
+        // 入りきらない文字列は書き込まず、ファイル名は不明にする
+        let file = "f".repeat(1000);
+        log.log_at(
+            Level::Warn,
+            Some(&file),
+            Some(7),
+            Some("app"),
+            &[],
+            format_args!("x"),
+        )
+        .unwrap();
+        let records = log.records().unwrap();
+        let strings = StringTable::from_records(&records);
+        let record = records.last().unwrap();
+        assert_eq!(record.text(), "x");
+        assert_eq!(record.location.unwrap().file, 0);
+        assert_eq!(strings.name(record.location.unwrap().module), "app");
+        assert!(records
+            .iter()
+            .filter(|record| record.kind == RecordKind::Str)
+            .all(|record| record.payload.len() < 100));
+
+        // 書式化前のログはIDが欠けるので書き込まない
+        let payload = vec![0; 1000];
+        assert_eq!(
+            log.lock()
+                .append(RecordKind::Deferred, Some(Level::Info), None, &[], &payload),
+            Err(FramError::TooLarge(1000))
+        );
+        assert_eq!(log.records().unwrap().len(), records.len());
+    }
+
+    // 書き込みの途中の各byteで電源を切り、そのときのFRAMの中身を順に返す
+    // 最後は、電源が切れずに書き込めたときの中身
+    fn cut_power_during(
+        base: &[u8],
+        write: impl Fn(&FramLog) -> Result<(), FramError>,
+    ) -> Vec<Vec<u8>> {
+        let mut images = Vec::new();
+        for limit in 0.. {
+            let mut fram = MemFram::from_bytes(base.to_vec());
+            fram.cut_power_after(limit);
+            // 開くときのヘッダの書き込みで切れた場合は、書き込みまで進まない
+            let Ok(log) = FramLog::new(fram) else {
+                continue;
+            };
+            let done = write(&log).is_ok();
+            images.push(image(&log));
+            if done {
+                break;
+            }
+        }
+        images
+    }
+
+    #[test]
+    fn rejects_small_device() {
+        let min = HEADER_SIZE as usize + RECORD_HEADER_SIZE + MAX_EXTENSION_SIZE;
+        for capacity in [0, 1, 0x40, 0x50, min] {
+            assert!(matches!(
+                FramLog::new(MemFram::new(capacity)),
+                Err(FramError::OutOfRange { .. })
+            ));
+        }
+
+        // 最小の大きさでも、書き込みでpanicしない
+        let clock = FakeClock::new();
+        clock.set_wall_time(Some(UNIX_EPOCH + Duration::from_secs(1_714_566_896)));
+        let log = FramLog::with_clock(MemFram::new(min + 1), clock).unwrap();
+        log.print(format_args!("hello")).unwrap();
+        log.log_at(
+            Level::Info,
+            Some("a.rs"),
+            Some(1),
+            Some("a"),
+            &[],
+            format_args!("x"),
+        )
         .unwrap();
+        assert_eq!(
+            log.lock()
+                .append(RecordKind::Deferred, Some(Level::Info), None, &[], &[0; 13]),
+            Err(FramError::TooLarge(13))
+        );
+    }
+
+    #[test]
+    fn skips_torn_records() {
+        let log = FramLog::new(MemFram::new(0x400)).unwrap();
+        for i in 0..5 {
+            log.log(Level::Info, format_args!("old {}", i)).unwrap();
+        }
+        let old = texts(&log);
+        let mut new = old.clone();
+        new.push("new message".to_string());
+
+        let images = cut_power_during(&image(&log), |log| {
+            log.log(Level::Warn, format_args!("new message"))
+        });
+        assert!(images.len() > 30);
+        for image in &images {
+            let log = FramLog::new(MemFram::from_bytes(image.clone())).unwrap();
+            let texts = texts(&log);
+            assert!(texts == old || texts == new, "{:?}", texts);
+
+            // ヘッダが壊れていても、途中まで書き込んだレコードはCRCで除かれる
+            let mut image = image.clone();
+            image[0] ^= 0xFF;
+            let image = Image::read(&image).unwrap();
+            assert!(image.salvaged);
+            let texts: Vec<_> = image
+                .records
+                .iter()
+                .filter(|record| record.kind == RecordKind::Log)
+                .map(|record| record.text().into_owned())
+                .collect();
+            assert!(texts == old || texts == new, "{:?}", texts);
+        }
+        let log = FramLog::new(MemFram::from_bytes(images.last().unwrap().clone())).unwrap();
+        assert_eq!(texts(&log), new);
+    }
+
+    #[test]
+    fn keeps_suffix_when_making_room() {
+        let log = FramLog::new(MemFram::new(0x200)).unwrap();
+        for i in 0..30 {
+            log.log(Level::Info, format_args!("old {}", i)).unwrap();
+        }
+        assert!(log.lock().header.wrapped);
+        let old = texts(&log);
+
+        // 古いレコードをいくつか捨てないと入らない長さ
+        let message = "n".repeat(100);
+        let images = cut_power_during(&image(&log), |log| {
+            log.log(Level::Warn, format_args!("{}", message))
+        });
+        assert!(images.len() > 100);
+        let mut dropped_only = false;
+        for image in &images {
+            let log = FramLog::new(MemFram::from_bytes(image.clone())).unwrap();
+            let mut texts = texts(&log);
+            // 新しいレコードは、ないか完全か
+            if texts.last() == Some(&message) {
+                texts.pop();
+            } else if texts.len() < old.len() {
+                // 古いレコードを捨てたところで切れた
+                dropped_only = true;
+            }
+            assert!(
+                texts.iter().all(|text| text.starts_with("old ")),
+                "{:?}",
+                texts
+            );
+            // 残った古いレコードは、元の並びの後ろの部分
+            assert!(old.ends_with(&texts), "{:?}", texts);
+        }
+        assert!(dropped_only);
+        let log = FramLog::new(MemFram::from_bytes(images.last().unwrap().clone())).unwrap();
+        assert_eq!(texts(&log).last(), Some(&message));
+        assert!(texts(&log).len() < old.len());
+    }
+
+    #[test]
+    fn records_clock() {
+        let clock = FakeClock::new();
+        let log = FramLog::with_clock(MemFram::new(0x2000), clock.clone()).unwrap();
+        clock.advance(Duration::from_millis(1500));
+        log.log(Level::Info, format_args!("before sync")).unwrap();
+
+        let now: WallTime = "2024-05-01T12:34:56.789Z".parse().unwrap();
+        clock.set_wall_time(Some(UNIX_EPOCH + Duration::from_millis(now.0)));
+        clock.advance(Duration::from_millis(250));
+        log.log(Level::Info, format_args!("after sync")).unwrap();
+
+        let records = log.records().unwrap();
+        assert_eq!(records[0].timestamp, 1500);
+        assert_eq!(records[0].wall_time, None);
+        assert_eq!(records[1].timestamp, 1750);
+        assert_eq!(records[1].wall_time, Some(now.0 + 250));
+
+        let strings = StringTable::from_records(&records);
+        assert!(records[1]
+            .display(&strings)
+            .to_string()
+            .starts_with("[1:    1750] 2024-05-01T12:34:57.039Z INFO"));
+    }
+
+    // 決まった順にリセットの原因を返す
+    struct FakeResetReason(Mutex<Vec<ResetReason>>);
+
+    impl ResetReasonSource for FakeResetReason {
+        fn reset_reason(&self) -> ResetReason {
+            self.0.lock().unwrap().remove(0)
+        }
+    }
+
+    #[test]
+    fn marks_boot_with_reset_reason() {
+        let reasons = [
+            ResetReason::PowerOn,
+            ResetReason::Watchdog,
+            ResetReason::Panic,
+            ResetReason::Brownout,
+        ];
+        let source = FakeResetReason(Mutex::new(reasons.to_vec()));
+        let mut log = FramLog::new(MemFram::new(0x2000)).unwrap();
+        for _ in reasons {
+            log.mark_boot(&source).unwrap();
+            log.log(Level::Info, format_args!("running")).unwrap();
+            log = reopen(&log);
+        }
+
+        let records = log.records().unwrap();
+        let boots: Vec<_> = records
+            .iter()
+            .filter_map(|record| Some((record.boot, record.reset_reason()?)))
+            .collect();
+        assert_eq!(
+            boots,
+            [
+                (1, ResetReason::PowerOn),
+                (2, ResetReason::Watchdog),
+                (3, ResetReason::Panic),
+                (4, ResetReason::Brownout),
+            ]
+        );
+
+        let mut text = Vec::new();
+        ExportFormat::Text
+            .write(
+                &mut text,
+                &records,
+                &StringTable::new(),
+                &ExportFilter::new(),
+            )
+            .unwrap();
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .contains("---- boot 2 (watchdog) ----\n"));
+    }
+
+    #[test]
+    fn logs_deferred() {
+        const FMT: &str = "rpm = {}, temp = {:.1}, {}";
+        static ENTRY: [u8; deferred::entry_len(FMT)] = deferred::entry(FMT);
+
+        let log = FramLog::new(MemFram::new(0x2000)).unwrap();
+        let args = [1200.to_value(), 36.55.to_value(), "ok".to_value()];
+        log.log_deferred(Level::Warn, &ENTRY, &args).unwrap();
+
+        let records = log.records().unwrap();
+        assert_eq!(records[0].kind, RecordKind::Deferred);
+        assert_eq!(records[0].level, Some(Level::Warn));
+        let mut strings = StringTable::from_records(&records);
+        strings.add_formats(&ENTRY);
+        assert_eq!(
+            records[0].message(&strings),
+            format!("rpm = {}, temp = {:.1}, {}", 1200, 36.55, "ok")
+        );
+    }
+
+    #[test]
+    fn filters_deferred_by_fram_sink() {
+        let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+        let logger = FramLogger::new()
+            .sink(
+                Filter::new(LevelFilter::Warn).module("app::motor", LevelFilter::Debug),
+                FramSink::new(Format::Message),
+            )
+            .sink(
+                Filter::new(LevelFilter::Trace),
+                ConsoleSink::new(Format::Level),
+            );
+
+        // シリアルのフィルタは通っても、FRAMのフィルタで判定する
+        let logger = Some(&logger);
+        assert!(deferred_enabled(logger, &metadata(Level::Warn, "app")));
+        assert!(!deferred_enabled(logger, &metadata(Level::Info, "app")));
+        assert!(deferred_enabled(
+            logger,
+            &metadata(Level::Debug, "app::motor::pid")
+        ));
+        assert!(!deferred_enabled(
+            logger,
+            &metadata(Level::Trace, "app::motor")
+        ));
+
+        // FRAMの出力先がなければ書き込まない
+        let console = FramLogger::new().sink(
+            Filter::new(LevelFilter::Trace),
+            ConsoleSink::new(Format::Level),
+        );
+        assert!(!deferred_enabled(
+            Some(&console),
+            &metadata(Level::Error, "app")
+        ));
+
+        // ロガーを登録していなければ、Info以上
+        assert!(deferred_enabled(None, &metadata(Level::Info, "app")));
+        assert!(!deferred_enabled(None, &metadata(Level::Debug, "app")));
+    }
+
+    #[test]
+    fn dumps_raw_image() {
+        let log = FramLog::new(MemFram::new(0x400)).unwrap();
+        for i in 0..10 {
+            log.log(Level::Info, format_args!("message {}", i)).unwrap();
+        }
+        let mut lines = Vec::new();
+        log.write_raw(|line| lines.push(line.to_string())).unwrap();
+        assert_eq!(lines, RawEncoder::encode(&image(&log)));
+        assert_eq!(RawDecoder::decode(&lines.join("\n")), Ok(image(&log)));
+    }
+
+    #[test]
+    fn shows_same_text_as_export() {
+        let log = FramLog::new(MemFram::new(0x2000)).unwrap();
+        log.log(Level::Info, format_args!("first")).unwrap();
+        let log = reopen(&log);
+        log.mark_boot(&ResetReason::Watchdog).unwrap();
+        log.log(Level::Warn, format_args!("second")).unwrap();
+        log.log(Level::Warn, format_args!("third")).unwrap();
+
+        let mut text = Vec::new();
+        log.write_log(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[2], "Log - - - - - - - - - - - - - - -");
+        // 起動のレコードがない起動も区切る
+        assert_eq!(lines[3], "---- boot 1 ----");
+        assert!(lines[4].ends_with("INFO - first"));
+        assert_eq!(lines[5], "---- boot 2 (watchdog) ----");
+        assert!(lines[6].ends_with("WARN - second"));
+
+        let image = Image::read(&image(&log)).unwrap();
+        let mut export = Vec::new();
+        ExportFormat::Text
+            .write(
+                &mut export,
+                &image.records,
+                &image.strings(),
+                &ExportFilter::new(),
+            )
+            .unwrap();
+        assert!(text.contains(&*String::from_utf8(export).unwrap()));
+
+        // 壊れたレコードの数
+        let second = log.records().unwrap()[2].clone();
+        let adrs = HEADER_SIZE as usize
+            + log.records().unwrap()[..2]
+                .iter()
+                .map(Record::encoded_size)
+                .sum::<usize>();
+        assert_eq!(second.text(), "second");
+        log.lock()
+            .device
+            .write(adrs as u32 + second.encoded_size() as u32 - 1, b"?")
+            .unwrap();
+        let mut text = Vec::new();
+        log.write_log(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(!text.contains("second"));
+        assert!(text.contains("third"));
+        assert!(text.contains("(1 corrupt record(s) skipped)\n- - -"));
+    }
 }
@@ -0,0 +1,272 @@
+// ログのタイムスタンプに使う時計
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// 時計
+pub trait Clock: Send {
+    // 起動からの時間(単調増加)
+    fn uptime(&self) -> Duration;
+
+    // 現在時刻(SNTPやRTCで合わせていなければNone)
+    fn wall_time(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+// これより前の時刻は、まだ合わせていないものとみなす(2024-01-01)
+const VALID_SINCE: Duration = Duration::from_secs(1_704_067_200);
+
+// 標準ライブラリの時計
+// ESP32ではSystemTimeは1970年から始まるので、SNTPなどで合わせるまではwall_timeはNone
+pub struct StdClock {
+    start: Instant,
+}
+
+impl StdClock {
+    pub fn new() -> Self {
+        StdClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for StdClock {
+    fn uptime(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn wall_time(&self) -> Option<SystemTime> {
+        let now = SystemTime::now();
+        match now.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) if since_epoch >= VALID_SINCE => Some(now),
+            _ => None,
+        }
+    }
+}
+
+// 手で進める時計(ホストでのテスト用)
+// cloneしたものは同じ時刻を共有するので、ロガーに渡した後も進められる
+#[derive(Clone, Default)]
+pub struct FakeClock {
+    state: Arc<Mutex<FakeState>>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    uptime: Duration,
+    wall_time: Option<SystemTime>,
+}
+
+impl FakeClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.uptime += duration;
+        state.wall_time = state.wall_time.map(|t| t + duration);
+    }
+
+    pub fn set_wall_time(&self, wall_time: Option<SystemTime>) {
+        self.state.lock().unwrap().wall_time = wall_time;
+    }
+}
+
+impl Clock for FakeClock {
+    fn uptime(&self) -> Duration {
+        self.state.lock().unwrap().uptime
+    }
+
+    fn wall_time(&self) -> Option<SystemTime> {
+        self.state.lock().unwrap().wall_time
+    }
+}
+
+// UNIX時間(ms)をUTCの日時として表示する
+pub struct WallTime(pub u64);
+
+impl fmt::Display for WallTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0 / 1000;
+        let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+        // 1970-01-01からの日数を年月日に変換する
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            rem / 3600,
+            rem / 60 % 60,
+            rem % 60,
+            self.0 % 1000
+        )
+    }
+}
+
+// "2024-05-01T12:34:56.789Z"のような文字列から作る
+// 日付のみ、秒やmsの省略、末尾のZの省略も受け付ける(UTCとみなす)
+impl std::str::FromStr for WallTime {
+    type Err = ParseWallTimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseWallTimeError(s.to_string());
+        let t = s.strip_suffix('Z').unwrap_or(s);
+        let (date, time) = t.split_once(['T', ' ']).unwrap_or((t, "00:00"));
+
+        let date: Vec<i64> = date
+            .split('-')
+            .map(|v| v.parse().map_err(|_| error()))
+            .collect::<Result<_, _>>()?;
+        let [year, month, day] = date[..] else {
+            return Err(error());
+        };
+        // 表示できる範囲の年だけ受け付ける(大きすぎる年で桁あふれしないように)
+        if !(1970..=9999).contains(&year) {
+            return Err(error());
+        }
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(error());
+        }
+
+        let (time, millis) = time.split_once('.').unwrap_or((time, "0"));
+        let time: Vec<u64> = time
+            .split(':')
+            .map(|v| v.parse().map_err(|_| error()))
+            .collect::<Result<_, _>>()?;
+        let (hour, minute, second) = match time[..] {
+            [hour, minute] => (hour, minute, 0),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return Err(error()),
+        };
+        if hour > 23 || minute > 59 || second > 59 || millis.len() > 3 {
+            return Err(error());
+        }
+        let millis: u64 = format!("{:0<3}", millis).parse().map_err(|_| error())?;
+
+        // 年月日を1970-01-01からの日数に変換する
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let mp = (month + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+
+        let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+        Ok(WallTime(secs * 1000 + millis))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseWallTimeError(String);
+
+impl fmt::Display for ParseWallTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid date and time '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseWallTimeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advances_fake_clock() {
+        let clock = FakeClock::new();
+        let shared = clock.clone();
+        assert_eq!(clock.uptime(), Duration::ZERO);
+        assert_eq!(clock.wall_time(), None);
+
+        // 時刻を合わせるまでは、起動からの時間だけ進む
+        shared.advance(Duration::from_millis(1500));
+        assert_eq!(clock.uptime(), Duration::from_millis(1500));
+        assert_eq!(clock.wall_time(), None);
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_714_566_896);
+        shared.set_wall_time(Some(now));
+        shared.advance(Duration::from_millis(250));
+        assert_eq!(clock.uptime(), Duration::from_millis(1750));
+        assert_eq!(clock.wall_time(), Some(now + Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn formats_wall_time() {
+        let table = [
+            (0, "1970-01-01T00:00:00.000Z"),
+            (951_782_400_000, "2000-02-29T00:00:00.000Z"),
+            (1_714_566_896_789, "2024-05-01T12:34:56.789Z"),
+            (253_402_300_799_999, "9999-12-31T23:59:59.999Z"),
+        ];
+        for (ms, s) in table {
+            assert_eq!(WallTime(ms).to_string(), s);
+            assert_eq!(s.parse::<WallTime>().unwrap().0, ms);
+        }
+    }
+
+    #[test]
+    fn round_trips_wall_time() {
+        let mut ms = 0;
+        while ms < 253_402_300_800_000 {
+            let wall_time: WallTime = WallTime(ms).to_string().parse().unwrap();
+            assert_eq!(wall_time.0, ms);
+            ms += 86_399_999 * 37;
+        }
+    }
+
+    #[test]
+    fn parses_short_forms() {
+        let table = [
+            ("2024-05-01", 1_714_521_600_000),
+            ("2024-05-01T12:34", 1_714_566_840_000),
+            ("2024-05-01 12:34:56", 1_714_566_896_000),
+            ("2024-05-01T12:34:56.7Z", 1_714_566_896_700),
+        ];
+        for (s, ms) in table {
+            assert_eq!(s.parse::<WallTime>().unwrap().0, ms, "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_wall_time() {
+        for s in [
+            "",
+            "2024",
+            "2024-05",
+            "2024-13-01",
+            "2024-05-32",
+            "2024-05-01T24:00",
+            "2024-05-01T12:60",
+            "2024-05-01T12:34:56.7890",
+            "1969-12-31",
+            "10000-01-01",
+            "9000000000000000-01-01",
+            "-9000000000000000-01-01",
+            "yesterday",
+        ] {
+            assert!(s.parse::<WallTime>().is_err(), "{}", s);
+        }
+    }
+}
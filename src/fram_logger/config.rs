@@ -0,0 +1,257 @@
+// FRAMロガーの設定
+//
+// let config = FramConfig::new()
+//     .part(FramPart::MB85RC256)
+//     .address(0x51)
+//     .baudrate(400_000);
+
+use super::FramPart;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramConfig {
+    // FRAMの仕様(Device IDで調べる場合は、調べられなかったときに使う)
+    pub part: FramPart,
+
+    // Device IDから容量を調べるか
+    pub detect_part: bool,
+
+    // I2Cスレーブアドレス(A0〜A2の設定を含む)
+    pub address: u8,
+
+    // I2Cの通信速度(Hz)
+    pub baudrate: u32,
+
+    // I2Cのタイムアウト(Noneならドライバの既定値)
+    pub timeout: Option<Duration>,
+}
+
+// FRAMのI2Cスレーブアドレスは0b1010xxx
+const ADDRESS_BASE: u8 = 0x50;
+const ADDRESS_MASK: u8 = 0xF8;
+
+// MB85RCシリーズの最大クロック
+const MAX_BAUDRATE: u32 = 1_000_000;
+
+// スレーブアドレスに入れられる上位ビットの数(A0〜A2)
+const MAX_PAGE_BITS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    // 0x50〜0x57以外のアドレス
+    InvalidAddress(u8),
+    // アドレスの上位ビットを入れる位置に、A0〜A2が設定されている
+    AddressOverlapsPage(u8),
+    // 0Hz、または最大クロックを超えている
+    InvalidBaudrate(u32),
+    // タイムアウトが0
+    InvalidTimeout,
+    // ドライバで扱えない仕様(アドレスが1〜2byteでない、容量が2のべき乗でない、
+    // 上位ビットがA0〜A2に入りきらない)
+    UnsupportedPart(FramPart),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::InvalidAddress(address) => {
+                write!(f, "I2C address {:#04x} is not a FRAM address", address)
+            }
+            ConfigError::AddressOverlapsPage(address) => write!(
+                f,
+                "I2C address {:#04x} uses bits reserved for the memory address",
+                address
+            ),
+            ConfigError::InvalidBaudrate(baudrate) => {
+                write!(f, "baudrate {} Hz is out of range", baudrate)
+            }
+            ConfigError::InvalidTimeout => write!(f, "timeout must not be zero"),
+            ConfigError::UnsupportedPart(part) => write!(
+                f,
+                "unsupported FRAM part: {:#x} bytes with {}-byte addresses",
+                part.capacity, part.address_bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for FramConfig {
+    fn default() -> Self {
+        FramConfig {
+            part: FramPart::MB85RC64,
+            detect_part: false,
+            address: ADDRESS_BASE,
+            baudrate: MAX_BAUDRATE,
+            timeout: None,
+        }
+    }
+}
+
+impl FramConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn part(mut self, part: FramPart) -> Self {
+        self.part = part;
+        self
+    }
+
+    pub fn detect_part(mut self, detect_part: bool) -> Self {
+        self.detect_part = detect_part;
+        self
+    }
+
+    pub fn address(mut self, address: u8) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn baudrate(mut self, baudrate: u32) -> Self {
+        self.baudrate = baudrate;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let part = self.part;
+        if !(1..=2).contains(&part.address_bytes)
+            || !part.capacity.is_power_of_two()
+            || part.page_bits() > MAX_PAGE_BITS
+        {
+            return Err(ConfigError::UnsupportedPart(part));
+        }
+
+        if self.address & ADDRESS_MASK != ADDRESS_BASE {
+            return Err(ConfigError::InvalidAddress(self.address));
+        }
+
+        let page_mask = (1u8 << self.part.page_bits()) - 1;
+        if self.address & page_mask != 0 {
+            return Err(ConfigError::AddressOverlapsPage(self.address));
+        }
+
+        if self.baudrate == 0 || self.baudrate > MAX_BAUDRATE {
+            return Err(ConfigError::InvalidBaudrate(self.baudrate));
+        }
+
+        if self.timeout == Some(Duration::ZERO) {
+            return Err(ConfigError::InvalidTimeout);
+        }
+
+        Ok(())
+    }
+
+    // スレーブアドレスを反映したFRAMの仕様
+    pub fn fram_part(&self) -> FramPart {
+        self.part.i2c_address(self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_default() {
+        assert_eq!(FramConfig::new().validate(), Ok(()));
+    }
+
+    #[test]
+    fn checks_address() {
+        for address in 0x50..=0x57 {
+            assert_eq!(FramConfig::new().address(address).validate(), Ok(()));
+        }
+        for address in [0x00, 0x4F, 0x58, 0x7F, 0xD0] {
+            assert_eq!(
+                FramConfig::new().address(address).validate(),
+                Err(ConfigError::InvalidAddress(address))
+            );
+        }
+    }
+
+    #[test]
+    fn checks_page_bits() {
+        // 型番ごとの、アドレスの上位ビットに使うA0〜A2
+        let table = [
+            (FramPart::MB85RC04, 0b001),
+            (FramPart::MB85RC16, 0b111),
+            (FramPart::MB85RC64, 0b000),
+            (FramPart::MB85RC256, 0b000),
+            (FramPart::MB85RC512, 0b000),
+            (FramPart::MB85RC1M, 0b001),
+        ];
+        for (part, page_mask) in table {
+            for bits in 0..8 {
+                let address = ADDRESS_BASE | bits;
+                let config = FramConfig::new().part(part).address(address);
+                if bits & page_mask == 0 {
+                    assert_eq!(config.validate(), Ok(()), "{:?} {:#04x}", part, address);
+                } else {
+                    assert_eq!(
+                        config.validate(),
+                        Err(ConfigError::AddressOverlapsPage(address)),
+                        "{:?} {:#04x}",
+                        part,
+                        address
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_part() {
+        let table = [
+            FramPart::new(0x20000, 1),
+            FramPart::new(0x2000, 0),
+            FramPart::new(0x2000, 4),
+            FramPart::new(0, 2),
+            FramPart::new(0x3000, 2),
+            FramPart::new(0x100000, 2),
+        ];
+        for part in table {
+            assert_eq!(
+                FramConfig::new().part(part).validate(),
+                Err(ConfigError::UnsupportedPart(part))
+            );
+        }
+        // A0〜A2をすべて使う最大の容量
+        let part = FramPart::new(0x80000, 2);
+        assert_eq!(FramConfig::new().part(part).validate(), Ok(()));
+    }
+
+    #[test]
+    fn checks_baudrate() {
+        for baudrate in [1, 100_000, 400_000, MAX_BAUDRATE] {
+            assert_eq!(FramConfig::new().baudrate(baudrate).validate(), Ok(()));
+        }
+        for baudrate in [0, MAX_BAUDRATE + 1, u32::MAX] {
+            assert_eq!(
+                FramConfig::new().baudrate(baudrate).validate(),
+                Err(ConfigError::InvalidBaudrate(baudrate))
+            );
+        }
+    }
+
+    #[test]
+    fn checks_timeout() {
+        assert_eq!(
+            FramConfig::new().timeout(Duration::ZERO).validate(),
+            Err(ConfigError::InvalidTimeout)
+        );
+        assert_eq!(
+            FramConfig::new()
+                .timeout(Duration::from_millis(1))
+                .validate(),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,21 @@
+// CRC-16/CCITT-FALSE (多項式0x1021, 初期値0xFFFF)
+
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0xFFFF, data)
+}
+
+// 分割されたデータのCRCを続けて計算する
+pub fn crc16_update(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = crc << 1 ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
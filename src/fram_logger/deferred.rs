@@ -0,0 +1,475 @@
+// 書式化を後回しにするログ(defmt風)
+//
+// flog!(Level::Info, "rpm = {}, temp = {:.1}", rpm, temp);
+//
+// 書式文字列はコンパイル時にハッシュ(ID)を付けて、マーカーとともにバイナリに埋め込む
+// FRAMにはIDと引数の値(fields.rsの値の形式)だけを書き込むので、書式化の処理がいらない
+// ホストでは、ELFファイルからマーカーを探して書式文字列の表を作り、テキストに戻す
+//
+// 埋め込む書式文字列
+//  0..6   マーカー "\0FLFMT"
+//  6..10  ID(書式文字列のFNV-1a、リトルエンディアン)
+//  10..   書式文字列(UTF-8)と終端の0
+//
+// 対応する書式は{}と{:?}、{0}のような位置指定、および幅、精度、x/X/o/b/eの指定
+// {name}のような名前での指定と、{:.*}や{:1$}のような引数での幅・精度の指定には対応せず、
+// コンパイル時にエラーにする
+// 整数は型の幅を残さないので、負の数をx/X/o/bで表示すると、Rustのような2の補数では
+// なく符号と絶対値になる(-1i8の{:x}は"ff"ではなく"-1"、{:#x}は"-0x1")
+
+use super::fields::FieldValue;
+use super::intern::fnv1a;
+use std::fmt::Write;
+
+pub const MARKER: &[u8; 6] = b"\0FLFMT";
+
+// 埋め込む書式文字列のbyte数
+pub const fn entry_len(fmt: &str) -> usize {
+    MARKER.len() + 4 + fmt.len() + 1
+}
+
+// 埋め込む書式文字列(Nはentry_len(fmt))
+pub const fn entry<const N: usize>(fmt: &str) -> [u8; N] {
+    assert!(
+        is_supported(fmt),
+        "flog! does not support named arguments or `.*`/`N$` width and precision"
+    );
+    let mut entry = [0; N];
+    let mut i = 0;
+    while i < MARKER.len() {
+        entry[i] = MARKER[i];
+        i += 1;
+    }
+    let id = fnv1a(fmt).to_le_bytes();
+    let mut j = 0;
+    while j < 4 {
+        entry[i + j] = id[j];
+        j += 1;
+    }
+    let bytes = fmt.as_bytes();
+    let mut k = 0;
+    while k < bytes.len() {
+        entry[i + 4 + k] = bytes[k];
+        k += 1;
+    }
+    entry
+}
+
+// renderで扱える書式文字列か
+// 位置は空か数字だけ、幅と精度に$と*を使わない({:$<5}のように埋める文字の$はよい)
+pub const fn is_supported(fmt: &str) -> bool {
+    let bytes = fmt.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        i += 1;
+        if (b == b'{' || b == b'}') && i < bytes.len() && bytes[i] == b {
+            i += 1;
+            continue;
+        }
+        if b != b'{' {
+            continue;
+        }
+
+        // 位置
+        while i < bytes.len() && bytes[i] != b':' && bytes[i] != b'}' {
+            if !bytes[i].is_ascii_digit() {
+                return false;
+            }
+            i += 1;
+        }
+        if i < bytes.len() && bytes[i] == b':' {
+            i += 1;
+            // 埋める文字(UTF-8の1文字)の後ろに揃え方があれば、埋める文字は飛ばす
+            let fill = match bytes.len() - i {
+                0 => 0,
+                _ if bytes[i] < 0x80 => 1,
+                _ if bytes[i] >= 0xF0 => 4,
+                _ if bytes[i] >= 0xE0 => 3,
+                _ => 2,
+            };
+            if i + fill < bytes.len() && matches!(bytes[i + fill], b'<' | b'^' | b'>') {
+                i += fill + 1;
+            }
+        }
+        while i < bytes.len() && bytes[i] != b'}' {
+            if bytes[i] == b'$' || bytes[i] == b'*' {
+                return false;
+            }
+            i += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+// 埋め込んだ書式文字列からIDと書式文字列を取り出す
+pub fn parse_entry(entry: &[u8]) -> Option<(u32, &str)> {
+    let rest = entry.strip_prefix(MARKER)?;
+    let id = u32::from_le_bytes(rest.get(..4)?.try_into().unwrap());
+    let rest = &rest[4..];
+    let end = rest.iter().position(|&b| b == 0)?;
+    let fmt = std::str::from_utf8(&rest[..end]).ok()?;
+    // たまたまマーカーと同じbyte列だった場合を除く
+    (fnv1a(fmt) == id).then_some((id, fmt))
+}
+
+// バイナリ(ELFファイルなど)から、埋め込まれた書式文字列をすべて探す
+pub fn scan(bytes: &[u8]) -> Vec<(u32, &str)> {
+    let mut found = Vec::new();
+    let mut i = 0;
+    while let Some(pos) = bytes[i..].windows(MARKER.len()).position(|w| w == MARKER) {
+        let start = i + pos;
+        if let Some(entry) = parse_entry(&bytes[start..]) {
+            found.push(entry);
+        }
+        i = start + 1;
+    }
+    found
+}
+
+// 書式化を後回しにできる引数
+pub trait DeferredArg {
+    fn to_value(&self) -> FieldValue;
+}
+
+macro_rules! deferred_int {
+    ($variant:ident, $as:ty, $($t:ty),*) => {
+        $(impl DeferredArg for $t {
+            fn to_value(&self) -> FieldValue {
+                FieldValue::$variant(*self as $as)
+            }
+        })*
+    };
+}
+
+deferred_int!(Int, i64, i8, i16, i32, i64, isize);
+deferred_int!(Uint, u64, u8, u16, u32, u64, usize);
+
+impl DeferredArg for f32 {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Float(*self as f64)
+    }
+}
+
+impl DeferredArg for f64 {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Float(*self)
+    }
+}
+
+impl DeferredArg for bool {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Bool(*self)
+    }
+}
+
+impl DeferredArg for char {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Str(self.to_string())
+    }
+}
+
+impl DeferredArg for str {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Str(self.to_string())
+    }
+}
+
+impl DeferredArg for String {
+    fn to_value(&self) -> FieldValue {
+        FieldValue::Str(self.clone())
+    }
+}
+
+impl<T: DeferredArg + ?Sized> DeferredArg for &T {
+    fn to_value(&self) -> FieldValue {
+        (**self).to_value()
+    }
+}
+
+// 書式文字列と引数からテキストを作る
+pub fn render(fmt: &str, args: &[FieldValue]) -> String {
+    let mut out = String::new();
+    let mut next = 0;
+    let mut chars = fmt.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek().map(|&(_, c)| c) == Some('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let Some(len) = fmt[i..].find('}') else {
+                    out.push_str(&fmt[i..]);
+                    break;
+                };
+                let placeholder = &fmt[i + 1..i + len];
+                while chars.peek().is_some_and(|&(j, _)| j <= i + len) {
+                    chars.next();
+                }
+
+                let (position, spec) = placeholder.split_once(':').unwrap_or((placeholder, ""));
+                let index = if position.is_empty() {
+                    next += 1;
+                    Some(next - 1)
+                } else {
+                    position.parse().ok()
+                };
+                match index.and_then(|index| args.get(index)) {
+                    Some(value) => Spec::parse(spec).write(&mut out, value),
+                    // 引数が足りない、または名前での指定はそのまま残す
+                    None => {
+                        let _ = write!(out, "{{{}}}", placeholder);
+                    }
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// {:...}の...の部分
+#[derive(Default)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<char>,
+    plus: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+impl Spec {
+    // [[fill]align][+][#][0][width][.precision][type]
+    fn parse(spec: &str) -> Spec {
+        let mut result = Spec::default();
+        let chars: Vec<char> = spec.chars().collect();
+        let mut i = 0;
+        let is_align = |c: char| matches!(c, '<' | '^' | '>');
+        if chars.len() >= 2 && is_align(chars[1]) {
+            result.fill = Some(chars[0]);
+            result.align = Some(chars[1]);
+            i = 2;
+        } else if chars.first().copied().is_some_and(is_align) {
+            result.align = Some(chars[0]);
+            i = 1;
+        }
+        if chars.get(i) == Some(&'+') {
+            result.plus = true;
+            i += 1;
+        }
+        if chars.get(i) == Some(&'#') {
+            result.alternate = true;
+            i += 1;
+        }
+        if chars.get(i) == Some(&'0') {
+            result.zero = true;
+            i += 1;
+        }
+        let digits = |i: &mut usize| {
+            let start = *i;
+            while chars.get(*i).is_some_and(|c| c.is_ascii_digit()) {
+                *i += 1;
+            }
+            chars[start..*i].iter().collect::<String>().parse().ok()
+        };
+        result.width = digits(&mut i).unwrap_or(0);
+        if chars.get(i) == Some(&'.') {
+            i += 1;
+            result.precision = digits(&mut i);
+        }
+        result.kind = chars.get(i).copied();
+        result
+    }
+
+    fn write(&self, out: &mut String, value: &FieldValue) {
+        let numeric = !matches!(value, FieldValue::Str(_) | FieldValue::Bool(_));
+        let (sign, body) = self.body(value);
+        let len = sign.chars().count() + body.chars().count();
+        let pad = self.width.saturating_sub(len);
+
+        // 0埋めは符号の後ろに入れる
+        if self.zero && numeric && self.align.is_none() {
+            out.push_str(&sign);
+            push_n(out, '0', pad);
+            out.push_str(&body);
+            return;
+        }
+
+        let fill = self.fill.unwrap_or(' ');
+        let align = self.align.unwrap_or(if numeric { '>' } else { '<' });
+        let (left, right) = match align {
+            '<' => (0, pad),
+            '^' => (pad / 2, pad - pad / 2),
+            _ => (pad, 0),
+        };
+        push_n(out, fill, left);
+        out.push_str(&sign);
+        out.push_str(&body);
+        push_n(out, fill, right);
+    }
+
+    // 符号(0xなどを含む)と、それ以降の部分
+    fn body(&self, value: &FieldValue) -> (String, String) {
+        let plus = if self.plus { "+" } else { "" };
+        match value {
+            FieldValue::Int(v) => {
+                let sign = if *v < 0 { "-" } else { plus };
+                let (prefix, digits) = self.radix(v.unsigned_abs());
+                (format!("{}{}", sign, prefix), digits)
+            }
+            FieldValue::Uint(v) => {
+                let (prefix, digits) = self.radix(*v);
+                (format!("{}{}", plus, prefix), digits)
+            }
+            FieldValue::Float(v) => {
+                let sign = if v.is_sign_negative() { "-" } else { plus };
+                let v = v.abs();
+                let body = match (self.kind, self.precision) {
+                    (Some('e'), Some(p)) => format!("{:.*e}", p, v),
+                    (Some('e'), None) => format!("{:e}", v),
+                    (_, Some(p)) => format!("{:.*}", p, v),
+                    (_, None) => format!("{}", v),
+                };
+                (sign.to_string(), body)
+            }
+            FieldValue::Bool(v) => (String::new(), v.to_string()),
+            FieldValue::Str(s) => {
+                let s: String = match self.precision {
+                    Some(p) => s.chars().take(p).collect(),
+                    None => s.clone(),
+                };
+                match self.kind {
+                    Some('?') => (String::new(), format!("{:?}", s)),
+                    _ => (String::new(), s),
+                }
+            }
+        }
+    }
+
+    // 0xなどと数字
+    fn radix(&self, v: u64) -> (&'static str, String) {
+        let (prefix, digits) = match self.kind {
+            Some('x') => ("0x", format!("{:x}", v)),
+            Some('X') => ("0x", format!("{:X}", v)),
+            Some('o') => ("0o", format!("{:o}", v)),
+            Some('b') => ("0b", format!("{:b}", v)),
+            Some('e') => ("", format!("{:e}", v)),
+            _ => ("", v.to_string()),
+        };
+        (if self.alternate { prefix } else { "" }, digits)
+    }
+}
+
+fn push_n(out: &mut String, c: char, n: usize) {
+    for _ in 0..n {
+        out.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // renderの結果がformat!と同じか
+    macro_rules! check {
+        ($fmt:literal $(, $arg:expr)*) => {
+            assert_eq!(
+                render($fmt, &[$(DeferredArg::to_value(&$arg)),*]),
+                format!($fmt $(, $arg)*),
+                "{}",
+                $fmt
+            )
+        };
+    }
+
+    const FMT: &str = "rpm = {}, temp = {:.1}";
+    static ENTRY: [u8; entry_len(FMT)] = entry(FMT);
+
+    #[test]
+    fn parses_entry() {
+        assert_eq!(parse_entry(&ENTRY), Some((fnv1a(FMT), FMT)));
+        assert_eq!(ENTRY.len(), MARKER.len() + 4 + FMT.len() + 1);
+        assert_eq!(ENTRY.last(), Some(&0));
+
+        // IDが合わない、終端がない、マーカーがない
+        let mut wrong_id = ENTRY;
+        wrong_id[MARKER.len()] ^= 1;
+        assert_eq!(parse_entry(&wrong_id), None);
+        assert_eq!(parse_entry(&ENTRY[..ENTRY.len() - 1]), None);
+        assert_eq!(parse_entry(&ENTRY[1..]), None);
+    }
+
+    #[test]
+    fn scans_binary() {
+        const OTHER: &str = "{:x}";
+        static OTHER_ENTRY: [u8; entry_len(OTHER)] = entry(OTHER);
+
+        let mut bytes = b"\x7fELF garbage \0FLFMT\x01\x02\x03\x04not an entry\0".to_vec();
+        bytes.extend_from_slice(&ENTRY);
+        bytes.extend_from_slice(b"\0\0more");
+        bytes.extend_from_slice(&OTHER_ENTRY);
+        // 途中で切れている
+        bytes.extend_from_slice(&ENTRY[..ENTRY.len() - 3]);
+        assert_eq!(scan(&bytes), [(fnv1a(FMT), FMT), (fnv1a(OTHER), OTHER)]);
+    }
+
+    #[test]
+    fn renders_like_format() {
+        check!("no arguments {{}}");
+        check!("{} {} {}", 42, -7i32, 3u8);
+        check!("{1} {0} {1}", "a", "b");
+        check!("{:5}|{:<5}|{:^5}|{:>5}|", 42, 42, 42, 42);
+        check!("{:05} {:+} {:+05}", -42, 7, 7);
+        check!("{:x} {:X} {:o} {:b}", 255u32, 255u32, 8u8, 5u8);
+        check!("{:#x} {:#010b} {:08X}", 255u32, 5u8, 0xBEEFu16);
+        check!("{:.2} {:8.3} {:<8.1}|", 1.23456, -2.5, 0.25);
+        check!("{:e} {:.2e} {:e}", 1234.5, 1234.5, 1234);
+        check!("{} {:?} {:.3} {:*^9}", "s", "a\"b", "abcdef", "mid");
+        check!("{} {:>6} {}", true, false, 'c');
+        check!("{:?} {:?}", 42, -1.5);
+    }
+
+    #[test]
+    fn checks_supported_formats() {
+        let table = [
+            ("plain {{}} }}{{", true),
+            ("{} {:?} {0} {1:>5} {:#010x} {:.3} {:+.1e}", true),
+            ("{:$<5} {:*^9} {:あ>3} {:.<4}", true),
+            ("{name}", false),
+            ("{x:?}", false),
+            ("{} {rpm}", false),
+            ("{:.*}", false),
+            ("{:1$}", false),
+            ("{:.1$}", false),
+            ("{:width$}", false),
+            ("{0:>.prec$}", false),
+        ];
+        for (fmt, supported) in table {
+            assert_eq!(is_supported(fmt), supported, "{}", fmt);
+        }
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        // 引数が足りない、名前での指定
+        assert_eq!(render("{} {} {name}", &[FieldValue::Int(1)]), "1 {} {name}");
+        assert_eq!(render("{3}", &[]), "{3}");
+        assert_eq!(render("open {", &[]), "open {");
+    }
+
+    #[test]
+    fn renders_negative_radix_with_sign() {
+        // 型の幅が分からないので、2の補数ではなく符号と絶対値になる
+        let args = [FieldValue::Int(-1), FieldValue::Int(-255)];
+        assert_eq!(render("{:x} {:#X}", &args), "-1 -0xFF");
+    }
+}
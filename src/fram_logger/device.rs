@@ -0,0 +1,434 @@
+// FRAMへのアクセスを抽象化するトレイトと、その実装
+
+use super::device_id::DeviceId;
+use super::FramError;
+
+// FRAMデバイス
+pub trait FramDevice {
+    // 容量(byte)
+    fn capacity(&self) -> usize;
+
+    // adrsからdata.len()byte読み込む
+    fn read(&mut self, adrs: u32, data: &mut [u8]) -> Result<(), FramError>;
+
+    // adrsからdataを書き込む
+    fn write(&mut self, adrs: u32, data: &[u8]) -> Result<(), FramError>;
+
+    // FRAMが応答するか確かめる
+    fn probe(&mut self) -> Result<(), FramError> {
+        Ok(())
+    }
+
+    // Device IDを読み込む(対応していなければNone)
+    fn device_id(&mut self) -> Result<Option<DeviceId>, FramError> {
+        Ok(None)
+    }
+}
+
+// メモリ上のFRAM(ホストでのテスト用)
+pub struct MemFram {
+    memory: Vec<u8>,
+
+    // 書き込めるbyte数の残り(電源断のシミュレーション用)
+    write_limit: Option<usize>,
+}
+
+impl MemFram {
+    pub fn new(capacity: usize) -> Self {
+        Self::from_bytes(vec![0; capacity])
+    }
+
+    // FRAMのイメージから作る
+    pub fn from_bytes(memory: Vec<u8>) -> Self {
+        MemFram {
+            memory,
+            write_limit: None,
+        }
+    }
+
+    // あとlimit byte書き込んだところで電源が切れたことにする
+    // それ以降の書き込みは途中で打ち切られ、FramError::Nackを返す
+    pub fn cut_power_after(&mut self, limit: usize) {
+        self.write_limit = Some(limit);
+    }
+
+    // 中身をそのまま参照する
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.memory
+    }
+}
+
+impl FramDevice for MemFram {
+    fn capacity(&self) -> usize {
+        self.memory.len()
+    }
+
+    fn read(&mut self, adrs: u32, data: &mut [u8]) -> Result<(), FramError> {
+        let start = adrs as usize;
+        let end = start + data.len();
+        if end > self.memory.len() {
+            return Err(FramError::OutOfRange {
+                adrs,
+                len: data.len(),
+            });
+        }
+        data.copy_from_slice(&self.memory[start..end]);
+        Ok(())
+    }
+
+    fn write(&mut self, adrs: u32, data: &[u8]) -> Result<(), FramError> {
+        let start = adrs as usize;
+        let end = start + data.len();
+        if end > self.memory.len() {
+            return Err(FramError::OutOfRange {
+                adrs,
+                len: data.len(),
+            });
+        }
+
+        if let Some(limit) = self.write_limit {
+            let n = limit.min(data.len());
+            self.memory[start..start + n].copy_from_slice(&data[..n]);
+            self.write_limit = Some(limit - n);
+            if n < data.len() {
+                return Err(FramError::Nack);
+            }
+            return Ok(());
+        }
+
+        self.memory[start..end].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+pub use self::i2c::I2cFram;
+
+mod i2c {
+    use super::super::device_id::DEVICE_ID_ADDRESS;
+    use super::super::part::{FramPart, MAX_CHUNK_SIZE};
+    use super::{DeviceId, FramDevice, FramError};
+    use embedded_hal::i2c::I2c;
+
+    // I2C接続のFRAM
+    // embedded-halのI2cを実装していれば何でもよいので、バスを共有する場合は
+    // embedded-hal-busのMutexDeviceなどを渡す
+    pub struct I2cFram<I> {
+        i2c: I,
+        part: FramPart,
+    }
+
+    impl<I: I2c> I2cFram<I> {
+        pub fn new(i2c: I, part: FramPart) -> Self {
+            assert!(part.chunk_size > 0 && part.chunk_size <= MAX_CHUNK_SIZE);
+            assert!(part.read_chunk_size > 0);
+            I2cFram { i2c, part }
+        }
+
+        // I2Cを返す
+        pub fn release(self) -> I {
+            self.i2c
+        }
+
+        pub fn part(&self) -> FramPart {
+            self.part
+        }
+
+        // 範囲外のアドレスはFRAMの中で折り返してしまうので、先に弾く
+        fn check_range(&self, adrs: u32, len: usize) -> Result<(), FramError> {
+            if adrs as usize + len > self.capacity() {
+                return Err(FramError::OutOfRange { adrs, len });
+            }
+            Ok(())
+        }
+
+        // adrsから、メモリアドレスの境界までのbyte数
+        fn page_remaining(&self, adrs: u32) -> usize {
+            let page_size = self.part.page_size();
+            (page_size - adrs % page_size) as usize
+        }
+
+        fn write_chunk(&mut self, adrs: u32, data: &[u8]) -> Result<(), FramError> {
+            let mut buffer: [u8; 2 + MAX_CHUNK_SIZE] = [0; 2 + MAX_CHUNK_SIZE];
+
+            // 先頭にメモリアドレスを付けて送る
+            let n = self.part.encode_address(adrs, &mut buffer);
+            buffer[n..n + data.len()].copy_from_slice(data);
+            self.i2c
+                .write(self.part.slave_address(adrs), &buffer[..n + data.len()])
+                .map_err(FramError::from_i2c)?;
+            Ok(())
+        }
+    }
+
+    impl<I: I2c> FramDevice for I2cFram<I> {
+        fn capacity(&self) -> usize {
+            self.part.capacity as usize
+        }
+
+        fn read(&mut self, adrs: u32, data: &mut [u8]) -> Result<(), FramError> {
+            self.check_range(adrs, data.len())?;
+
+            // read_chunk_sizeずつ、メモリアドレスの境界をまたがないように読み込む
+            let mut adrs = adrs;
+            let mut data = data;
+            while !data.is_empty() {
+                let len = data
+                    .len()
+                    .min(self.part.read_chunk_size)
+                    .min(self.page_remaining(adrs));
+                let (chunk, rest) = data.split_at_mut(len);
+
+                // アドレスの書き込みと読み込みは、STOPを挟まずに1回の転送で行う
+                // (間に他の通信が入ると、FRAMの内部アドレスがずれてしまう)
+                let mut buffer: [u8; 2] = [0; 2];
+                let n = self.part.encode_address(adrs, &mut buffer);
+                self.i2c
+                    .write_read(self.part.slave_address(adrs), &buffer[..n], chunk)
+                    .map_err(FramError::from_i2c)?;
+
+                data = rest;
+                adrs += len as u32;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, adrs: u32, data: &[u8]) -> Result<(), FramError> {
+            self.check_range(adrs, data.len())?;
+
+            // chunk_sizeずつ、メモリアドレスの境界をまたがないように書き込む
+            let mut adrs = adrs;
+            let mut data = data;
+            while !data.is_empty() {
+                let len = data
+                    .len()
+                    .min(self.part.chunk_size)
+                    .min(self.page_remaining(adrs));
+                self.write_chunk(adrs, &data[..len])?;
+                data = &data[len..];
+                adrs += len as u32;
+            }
+            Ok(())
+        }
+
+        fn probe(&mut self) -> Result<(), FramError> {
+            // 先頭の1byteを読んで、ACKが返るか確かめる
+            let mut data: [u8; 1] = [0];
+            self.read(0, &mut data)
+        }
+
+        fn device_id(&mut self) -> Result<Option<DeviceId>, FramError> {
+            let mut bytes: [u8; 3] = [0; 3];
+            let address = [self.part.i2c_address << 1];
+            match self.i2c.write_read(DEVICE_ID_ADDRESS, &address, &mut bytes) {
+                Ok(()) => Ok(Some(DeviceId::from_bytes(bytes))),
+                // Device IDに対応していない型番はNACKを返す
+                Err(e) => match FramError::from_i2c(e) {
+                    FramError::Nack => Ok(None),
+                    e => Err(e),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::part::{FramPart, MAX_CHUNK_SIZE};
+    use super::*;
+    use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+    // スレーブアドレスへの、メモリアドレスとデータの書き込み
+    fn write(slave: u8, address: &[u8], data: &[u8]) -> Transaction {
+        Transaction::write(slave, [address, data].concat())
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (1..=len).map(|i| i as u8).collect()
+    }
+
+    // expectationsの通りに転送されるか確かめる
+    fn check_write(part: FramPart, adrs: u32, data: &[u8], expectations: &[Transaction]) {
+        let mut fram = I2cFram::new(Mock::new(expectations), part);
+        fram.write(adrs, data).unwrap();
+        fram.release().done();
+    }
+
+    #[test]
+    fn writes_with_page_in_slave_address() {
+        // 1byteのメモリアドレスで、上位ビットはスレーブアドレスに入れる
+        let data = data(0x20);
+        check_write(
+            FramPart::MB85RC04,
+            0xF0,
+            &data,
+            &[
+                write(0x50, &[0xF0], &data[..0x10]),
+                write(0x51, &[0x00], &data[0x10..]),
+            ],
+        );
+        check_write(
+            FramPart::MB85RC16,
+            0x7F8,
+            &data[..8],
+            &[write(0x57, &[0xF8], &data[..8])],
+        );
+        check_write(
+            FramPart::MB85RC16,
+            0x3FC,
+            &data[..8],
+            &[
+                write(0x53, &[0xFC], &data[..4]),
+                write(0x54, &[0x00], &data[4..8]),
+            ],
+        );
+    }
+
+    #[test]
+    fn writes_in_chunks() {
+        // 2byteのメモリアドレスで、chunk_sizeずつ
+        let data = data(70);
+        check_write(
+            FramPart::MB85RC64,
+            0x1234,
+            &data,
+            &[
+                write(0x50, &[0x12, 0x34], &data[..30]),
+                write(0x50, &[0x12, 0x52], &data[30..60]),
+                write(0x50, &[0x12, 0x70], &data[60..]),
+            ],
+        );
+        check_write(
+            FramPart::MB85RC256.chunk_size(MAX_CHUNK_SIZE),
+            0x7FC0,
+            &data[..64],
+            &[write(0x50, &[0x7F, 0xC0], &data[..64])],
+        );
+    }
+
+    #[test]
+    fn writes_across_page_of_1m() {
+        // MB85RC1Mは0x10000からスレーブアドレスのA0が1になる
+        let data = data(0x20);
+        check_write(
+            FramPart::MB85RC1M,
+            0xFFF0,
+            &data,
+            &[
+                write(0x50, &[0xFF, 0xF0], &data[..0x10]),
+                write(0x51, &[0x00, 0x00], &data[0x10..]),
+            ],
+        );
+        check_write(
+            FramPart::MB85RC1M.i2c_address(0x54),
+            0x1FFFC,
+            &data[..4],
+            &[write(0x55, &[0xFF, 0xFC], &data[..4])],
+        );
+    }
+
+    // メモリアドレスを書き込み、続けて読み込む
+    fn write_read(slave: u8, address: &[u8], data: &[u8]) -> Transaction {
+        Transaction::write_read(slave, address.to_vec(), data.to_vec())
+    }
+
+    fn check_read(part: FramPart, adrs: u32, data: &[u8], expectations: &[Transaction]) {
+        let mut fram = I2cFram::new(Mock::new(expectations), part);
+        let mut read = vec![0; data.len()];
+        fram.read(adrs, &mut read).unwrap();
+        assert_eq!(read, data);
+        fram.release().done();
+    }
+
+    #[test]
+    fn reads_in_chunks() {
+        // read_chunk_sizeずつ、1回のwrite_readで読み込む
+        let data = data(300);
+        check_read(
+            FramPart::MB85RC256,
+            0x100,
+            &data,
+            &[
+                write_read(0x50, &[0x01, 0x00], &data[..128]),
+                write_read(0x50, &[0x01, 0x80], &data[128..256]),
+                write_read(0x50, &[0x02, 0x00], &data[256..]),
+            ],
+        );
+        check_read(
+            FramPart::MB85RC64.read_chunk_size(16),
+            0x10,
+            &data[..40],
+            &[
+                write_read(0x50, &[0x00, 0x10], &data[..16]),
+                write_read(0x50, &[0x00, 0x20], &data[16..32]),
+                write_read(0x50, &[0x00, 0x30], &data[32..40]),
+            ],
+        );
+    }
+
+    #[test]
+    fn reads_across_page() {
+        // メモリアドレスの境界で分け、スレーブアドレスを変える
+        let data = data(0x40);
+        check_read(
+            FramPart::MB85RC04,
+            0xF8,
+            &data[..0x10],
+            &[
+                write_read(0x50, &[0xF8], &data[..8]),
+                write_read(0x51, &[0x00], &data[8..0x10]),
+            ],
+        );
+        check_read(
+            FramPart::MB85RC16,
+            0x0,
+            &data[..4],
+            &[write_read(0x50, &[0x00], &data[..4])],
+        );
+        check_read(
+            FramPart::MB85RC1M,
+            0xFFE0,
+            &data,
+            &[
+                write_read(0x50, &[0xFF, 0xE0], &data[..0x20]),
+                write_read(0x51, &[0x00, 0x00], &data[0x20..]),
+            ],
+        );
+        // read_chunk_sizeと境界の両方で分ける
+        check_read(
+            FramPart::MB85RC1M.read_chunk_size(0x18),
+            0xFFE0,
+            &data,
+            &[
+                write_read(0x50, &[0xFF, 0xE0], &data[..0x18]),
+                write_read(0x50, &[0xFF, 0xF8], &data[0x18..0x20]),
+                write_read(0x51, &[0x00, 0x00], &data[0x20..0x38]),
+                write_read(0x51, &[0x00, 0x18], &data[0x38..]),
+            ],
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        let mut fram = I2cFram::new(Mock::new(&[]), FramPart::MB85RC64);
+        assert!(matches!(
+            fram.write(0x1FFF, &[0, 0]),
+            Err(FramError::OutOfRange { .. })
+        ));
+        fram.write(0x2000, &[]).unwrap();
+        assert!(matches!(
+            fram.read(0x1FF0, &mut [0; 0x11]),
+            Err(FramError::OutOfRange { .. })
+        ));
+        fram.release().done();
+    }
+
+    #[test]
+    fn returns_nack() {
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let expectations = [write(0x50, &[0x00, 0x10], &[1, 2]).with_error(nack)];
+        let mut fram = I2cFram::new(Mock::new(&expectations), FramPart::MB85RC64);
+        assert!(matches!(fram.write(0x10, &[1, 2]), Err(FramError::Nack)));
+        fram.release().done();
+    }
+}
@@ -0,0 +1,137 @@
+// FRAMのDevice ID
+//
+// 予約スレーブアドレス0xF8(7bitでは0x7C)にFRAMのスレーブアドレスを書き込み、
+// リピートスタートで3byte読み込むと得られる
+//
+//  bit 23..12  メーカーID
+//  bit 11..0   プロダクトID (上位4bitが容量)
+//
+// 容量の値はメーカーによって異なる
+//
+//  Fujitsu  0x3: 64Kbit, 0x4: 128Kbit, 0x5: 256Kbit, 0x6: 512Kbit, 0x7: 1Mbit
+//  Cypress  0x1: 128Kbit, 0x2: 256Kbit, 0x3: 512Kbit, 0x4: 1Mbit
+
+use super::FramPart;
+
+// Device IDの予約スレーブアドレス
+pub const DEVICE_ID_ADDRESS: u8 = 0x7C;
+
+// メーカーID
+pub const FUJITSU: u16 = 0x00A;
+pub const CYPRESS: u16 = 0x004;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceId {
+    pub manufacturer: u16,
+    pub product: u16,
+}
+
+impl DeviceId {
+    pub fn from_bytes(bytes: [u8; 3]) -> Self {
+        DeviceId {
+            manufacturer: (bytes[0] as u16) << 4 | (bytes[1] >> 4) as u16,
+            product: ((bytes[1] & 0x0F) as u16) << 8 | bytes[2] as u16,
+        }
+    }
+
+    // 容量を表す4bit
+    pub fn density(&self) -> u8 {
+        (self.product >> 8) as u8
+    }
+
+    // 容量(byte)
+    // 知らないメーカーや値ならNone
+    pub fn capacity(&self) -> Option<u32> {
+        match (self.manufacturer, self.density()) {
+            (FUJITSU, density @ 0x3..=0x7) => Some(0x400 << density),
+            (CYPRESS, density @ 0x1..=0x4) => Some(0x2000 << density),
+            _ => None,
+        }
+    }
+
+    // baseの容量とアドレスのbyte数を、このFRAMに合わせる
+    pub fn part(&self, base: FramPart) -> Option<FramPart> {
+        base.with_capacity(self.capacity()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // メーカーID、プロダクトIDからDevice IDの3byteを作る
+    fn bytes(manufacturer: u16, product: u16) -> [u8; 3] {
+        [
+            (manufacturer >> 4) as u8,
+            (manufacturer << 4) as u8 | (product >> 8) as u8,
+            product as u8,
+        ]
+    }
+
+    #[test]
+    fn decodes_bytes() {
+        let id = DeviceId::from_bytes([0x00, 0xA5, 0x10]);
+        assert_eq!(id.manufacturer, FUJITSU);
+        assert_eq!(id.product, 0x510);
+        assert_eq!(id.density(), 0x5);
+    }
+
+    #[test]
+    fn detects_capacity() {
+        let table = [
+            (FUJITSU, 0x358, Some(FramPart::MB85RC64)),
+            (
+                FUJITSU,
+                0x410,
+                Some(FramPart::MB85RC64.with_capacity(0x4000).unwrap()),
+            ),
+            (FUJITSU, 0x510, Some(FramPart::MB85RC256)),
+            (FUJITSU, 0x658, Some(FramPart::MB85RC512)),
+            (FUJITSU, 0x758, Some(FramPart::MB85RC1M)),
+            (
+                CYPRESS,
+                0x100,
+                Some(FramPart::MB85RC64.with_capacity(0x4000).unwrap()),
+            ),
+            (CYPRESS, 0x200, Some(FramPart::MB85RC256)),
+            (CYPRESS, 0x300, Some(FramPart::MB85RC512)),
+            (CYPRESS, 0x400, Some(FramPart::MB85RC1M)),
+        ];
+        for (manufacturer, product, part) in table {
+            let id = DeviceId::from_bytes(bytes(manufacturer, product));
+            assert_eq!(id.part(FramPart::MB85RC64), part, "{:?}", id);
+            assert_eq!(id.capacity(), part.map(|part| part.capacity));
+        }
+    }
+
+    #[test]
+    fn keeps_base_settings() {
+        // 容量とアドレスのbyte数以外は、元の設定のまま
+        let base = FramPart::MB85RC64.i2c_address(0x52).chunk_size(16);
+        let part = DeviceId::from_bytes(bytes(FUJITSU, 0x510))
+            .part(base)
+            .unwrap();
+        assert_eq!(part, base.with_capacity(0x8000).unwrap());
+        assert_eq!(part.i2c_address, 0x52);
+        assert_eq!(part.chunk_size, 16);
+    }
+
+    #[test]
+    fn ignores_unknown_id() {
+        // 知らないIDなら、呼び出し側はconfig.partのまま使う
+        let table = [
+            (FUJITSU, 0x058),
+            (FUJITSU, 0x258),
+            (FUJITSU, 0x858),
+            (CYPRESS, 0x000),
+            (CYPRESS, 0x500),
+            (0x001, 0x510),
+            (0xFFF, 0xFFF),
+        ];
+        for (manufacturer, product) in table {
+            let id = DeviceId::from_bytes(bytes(manufacturer, product));
+            assert_eq!(id.capacity(), None, "{:?}", id);
+            assert_eq!(id.part(FramPart::MB85RC256), None);
+        }
+    }
+}
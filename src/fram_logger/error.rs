@@ -0,0 +1,63 @@
+// FRAMロガーのエラー
+
+use super::ConfigError;
+use embedded_hal::i2c::{self, ErrorKind};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FramError {
+    // FRAMがACKを返さない(接続されていない)
+    Nack,
+    // ロガーやバスが一定時間使えなかった
+    Timeout,
+    // NACK以外のバスのエラー
+    Bus(ErrorKind),
+    // FRAMの範囲外へのアクセス
+    OutOfRange { adrs: u32, len: usize },
+    // FRAMの内容が壊れている
+    Corrupt,
+    // 1つのレコードとして書き込めない大きさ(byte数)
+    TooLarge(usize),
+    // ロガーが初期化されていない
+    NotInitialized,
+    // ロガーが既に初期化されている
+    AlreadyInitialized,
+    // 設定が正しくない
+    Config(ConfigError),
+}
+
+impl FramError {
+    // embedded-halのI2Cのエラーから変換する
+    pub fn from_i2c(e: impl i2c::Error) -> Self {
+        match e.kind() {
+            ErrorKind::NoAcknowledge(_) => FramError::Nack,
+            kind => FramError::Bus(kind),
+        }
+    }
+}
+
+impl From<ConfigError> for FramError {
+    fn from(e: ConfigError) -> Self {
+        FramError::Config(e)
+    }
+}
+
+impl fmt::Display for FramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FramError::Nack => write!(f, "FRAM did not acknowledge"),
+            FramError::Timeout => write!(f, "FRAM access timed out"),
+            FramError::Bus(kind) => write!(f, "I2C bus error: {:?}", kind),
+            FramError::OutOfRange { adrs, len } => {
+                write!(f, "access out of range: {:#x} ({} bytes)", adrs, len)
+            }
+            FramError::Corrupt => write!(f, "FRAM contents are corrupt"),
+            FramError::TooLarge(len) => write!(f, "{} bytes do not fit in a record", len),
+            FramError::NotInitialized => write!(f, "FRAM logger is not initialized"),
+            FramError::AlreadyInitialized => write!(f, "FRAM logger is already initialized"),
+            FramError::Config(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FramError {}
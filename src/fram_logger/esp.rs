@@ -0,0 +1,64 @@
+// ESP32でのFRAMロガーの初期化
+
+use super::{FramConfig, FramLog, ResetReason};
+use anyhow::Ok;
+use embedded_hal_bus::i2c::MutexDevice;
+use esp_idf_hal::gpio::{InputPin, OutputPin};
+use esp_idf_hal::i2c::{APBTickType, I2c, I2cConfig, I2cDriver};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::prelude::*;
+use esp_idf_svc::sys;
+use std::sync::Mutex;
+
+// 他のデバイスと共有するI2Cバス
+// 他のドライバにはembedded_hal_bus::i2c::MutexDevice::new(bus)を渡す
+pub type SharedI2c = &'static Mutex<I2cDriver<'static>>;
+
+// I2Cの初期化
+pub fn i2c_master_init<'d>(
+    i2c: impl Peripheral<P = impl I2c> + 'd,
+    sda: impl Peripheral<P = impl InputPin + OutputPin> + 'd,
+    scl: impl Peripheral<P = impl InputPin + OutputPin> + 'd,
+    config: &FramConfig,
+) -> anyhow::Result<I2cDriver<'d>> {
+    let mut i2c_config = I2cConfig::new().baudrate(config.baudrate.Hz());
+    if let Some(timeout) = config.timeout {
+        i2c_config = i2c_config.timeout(APBTickType::from(timeout));
+    }
+    let driver = I2cDriver::new(i2c, sda, scl, &i2c_config)?;
+    Ok(driver)
+}
+
+// I2Cバス、SDA、SCLと設定を指定して初期化する
+// 他のデバイスとバスを共有する場合は、戻り値のSharedI2cをそちらで使う
+pub fn init(
+    i2c: impl Peripheral<P = impl I2c> + 'static,
+    sda: impl Peripheral<P = impl InputPin + OutputPin> + 'static,
+    scl: impl Peripheral<P = impl InputPin + OutputPin> + 'static,
+    config: &FramConfig,
+) -> anyhow::Result<(&'static FramLog, SharedI2c)> {
+    config.validate()?;
+    let driver = i2c_master_init(i2c, sda, scl, config)?;
+
+    // バスは最後まで使うので、リークさせて'staticにする
+    let bus: SharedI2c = Box::leak(Box::new(Mutex::new(driver)));
+    let log = super::init_shared(MutexDevice::new(bus), config)?;
+    Ok((log, bus))
+}
+
+// 直前のリセットの原因
+pub fn reset_reason() -> ResetReason {
+    #[allow(non_upper_case_globals)]
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => ResetReason::PowerOn,
+        sys::esp_reset_reason_t_ESP_RST_EXT => ResetReason::External,
+        sys::esp_reset_reason_t_ESP_RST_SW => ResetReason::Software,
+        sys::esp_reset_reason_t_ESP_RST_PANIC => ResetReason::Panic,
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT
+        | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+        | sys::esp_reset_reason_t_ESP_RST_WDT => ResetReason::Watchdog,
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => ResetReason::Brownout,
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => ResetReason::DeepSleep,
+        _ => ResetReason::Unknown,
+    }
+}
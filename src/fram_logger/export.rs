@@ -0,0 +1,571 @@
+// FRAMのイメージからのログの取り出し(fram-dumpで使う)
+//
+// プログラマなどで読み出したFRAMの中身から、レコードを古い順に取り出して
+// テキスト、JSON Lines、CSVのいずれかで出力する
+//
+// ヘッダが壊れている場合は、リングバッファ全体から正しいレコードを探し
+// シーケンス番号の順に並べる(上書き済みの古いレコードが混ざることがある)
+
+use super::clock::WallTime;
+use super::fields::{JsonFields, JsonStr, KeyValues};
+use super::header::{Header, HEADER_SIZE};
+use super::intern::StringTable;
+use super::record::{Record, RecordKind, Records, RECORD_HEADER_SIZE, SYNC};
+use super::ring::Ring;
+use super::{FramDevice, FramError, MemFram};
+use log::LevelFilter;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+// イメージから取り出したログ
+#[derive(Clone, Debug, Default)]
+pub struct Image {
+    pub records: Vec<Record>,
+    // 読み飛ばした壊れたレコードの数
+    pub corrupt: usize,
+    // ヘッダが使えず、リングバッファ全体から探した
+    pub salvaged: bool,
+}
+
+impl Image {
+    pub fn read(image: &[u8]) -> Result<Image, FramError> {
+        if image.len() as u32 <= HEADER_SIZE {
+            return Err(FramError::OutOfRange {
+                adrs: 0,
+                len: image.len(),
+            });
+        }
+        let mut device = MemFram::from_bytes(image.to_vec());
+        let ring = Ring::new(HEADER_SIZE, device.capacity() as u32);
+
+        match Header::load(&mut device) {
+            Ok(Some(header)) => {
+                let mut log = vec![0; ring.distance(header.head, header.tail)];
+                ring.read(&mut device, header.head, &mut log)?;
+                let mut image = Image::default();
+                for record in Records::new(&log) {
+                    match record {
+                        Ok(record) => image.records.push(record),
+                        Err(_) => image.corrupt += 1,
+                    }
+                }
+                Ok(image)
+            }
+            // 未フォーマット(マジックナンバーが壊れている場合を含む)
+            Ok(None) | Err(FramError::Corrupt) => {
+                Ok(Image::salvage(&image[HEADER_SIZE as usize..]))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // リングバッファの中の正しいレコードをすべて探す
+    // 終端をまたぐレコードも読めるように、先頭の一部を後ろにつなげて探す
+    fn salvage(area: &[u8]) -> Image {
+        let mut bytes = area.to_vec();
+        bytes.extend_from_slice(&area[..area.len().min(u16::MAX as usize + RECORD_HEADER_SIZE)]);
+
+        let mut records: Vec<Record> = (0..area.len())
+            .filter(|&i| bytes[i] == SYNC)
+            .filter_map(|i| Record::decode(&bytes[i..]).ok())
+            .map(|(record, _)| record)
+            .collect();
+        records.sort_by_key(|record| record.seq);
+        records.dedup_by_key(|record| record.seq);
+        Image {
+            records,
+            corrupt: 0,
+            salvaged: true,
+        }
+    }
+
+    // 最後の起動の起動回数
+    pub fn last_boot(&self) -> Option<u32> {
+        self.records.iter().map(|record| record.boot).max()
+    }
+
+    // ファイル名などの文字列の表
+    // 書式化前のログの書式文字列は、StringTable::add_formatsで追加する
+    pub fn strings(&self) -> StringTable {
+        StringTable::from_records(&self.records)
+    }
+}
+
+// 時刻の指定
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeBound {
+    // 起動からのms
+    Uptime(u32),
+    // UNIX時間のms
+    Wall(u64),
+}
+
+impl TimeBound {
+    fn value(&self, record: &Record) -> Option<u64> {
+        match self {
+            TimeBound::Uptime(_) => Some(record.timestamp as u64),
+            TimeBound::Wall(_) => record.wall_time,
+        }
+    }
+
+    fn bound(&self) -> u64 {
+        match *self {
+            TimeBound::Uptime(ms) => ms as u64,
+            TimeBound::Wall(ms) => ms,
+        }
+    }
+}
+
+// 数字だけなら起動からのms、それ以外は"2024-05-01T12:34:56Z"のような日時
+impl FromStr for TimeBound {
+    type Err = ParseExportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ms) = s.parse() {
+            return Ok(TimeBound::Uptime(ms));
+        }
+        match s.parse::<WallTime>() {
+            Ok(wall_time) => Ok(TimeBound::Wall(wall_time.0)),
+            Err(_) => Err(ParseExportError::new("time", s)),
+        }
+    }
+}
+
+// 出力するレコードの条件
+// テキストと起動の区切りにはレベルがないので、レベルでは除かない
+// 時刻を指定した場合、その時刻を持たないレコードは除く
+#[derive(Clone, Debug)]
+pub struct ExportFilter {
+    pub level: LevelFilter,
+    pub boots: Option<RangeInclusive<u32>>,
+    pub from: Option<TimeBound>,
+    pub to: Option<TimeBound>,
+}
+
+impl ExportFilter {
+    pub fn new() -> Self {
+        ExportFilter {
+            level: LevelFilter::Trace,
+            boots: None,
+            from: None,
+            to: None,
+        }
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        if record.kind == RecordKind::Str {
+            return false;
+        }
+        if record.level.is_some_and(|level| level > self.level) {
+            return false;
+        }
+        if let Some(boots) = &self.boots {
+            if !boots.contains(&record.boot) {
+                return false;
+            }
+        }
+        if let Some(from) = &self.from {
+            if from.value(record).filter(|&t| t >= from.bound()).is_none() {
+                return false;
+            }
+        }
+        if let Some(to) = &self.to {
+            if to.value(record).filter(|&t| t <= to.bound()).is_none() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Default for ExportFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 出力の形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    // show_logと同じ表示
+    Text,
+    // 1行に1レコードのJSON
+    Json,
+    // 1行目は列の名前
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = ParseExportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(ExportFormat::Text),
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(ParseExportError::new("format", s)),
+        }
+    }
+}
+
+const CSV_COLUMNS: &str = "seq,boot,timestamp,wall_time,kind,level,module,file,line,message,fields";
+
+impl ExportFormat {
+    // filterを通ったレコードを書き出す
+    pub fn write(
+        &self,
+        w: &mut dyn Write,
+        records: &[Record],
+        strings: &StringTable,
+        filter: &ExportFilter,
+    ) -> io::Result<()> {
+        if *self == ExportFormat::Csv {
+            writeln!(w, "{}", CSV_COLUMNS)?;
+        }
+        let mut boot = None;
+        for record in records.iter().filter(|record| filter.matches(record)) {
+            match self {
+                ExportFormat::Text => {
+                    // 起動のレコードが上書きされていても、起動ごとに区切る
+                    if boot != Some(record.boot) && record.kind != RecordKind::Boot {
+                        writeln!(w, "---- boot {} ----", record.boot)?;
+                    }
+                    boot = Some(record.boot);
+                    write!(w, "{}", record.display(strings))?;
+                }
+                ExportFormat::Json => write_json(w, record, strings)?,
+                ExportFormat::Csv => write_csv(w, record, strings)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// 起動のレコードのテキストはリセットの原因
+fn message(record: &Record, strings: &StringTable) -> String {
+    match record.reset_reason() {
+        Some(reason) => reason.to_string(),
+        None => record.message(strings),
+    }
+}
+
+fn write_json(w: &mut dyn Write, record: &Record, strings: &StringTable) -> io::Result<()> {
+    write!(
+        w,
+        "{{\"seq\":{},\"boot\":{},\"timestamp\":{},\"wall_time\":",
+        record.seq, record.boot, record.timestamp
+    )?;
+    match record.wall_time {
+        Some(wall_time) => write!(w, "\"{}\"", WallTime(wall_time))?,
+        None => write!(w, "null")?,
+    }
+    write!(w, ",\"kind\":\"{}\",\"level\":", record.kind.as_str())?;
+    match record.level {
+        Some(level) => write!(w, "\"{}\"", level)?,
+        None => write!(w, "null")?,
+    }
+    if let Some(location) = record.location {
+        if location.module != 0 {
+            write!(w, ",\"module\":{}", JsonStr(&strings.name(location.module)))?;
+        }
+        if location.file != 0 {
+            write!(w, ",\"file\":{}", JsonStr(&strings.name(location.file)))?;
+            write!(w, ",\"line\":{}", location.line)?;
+        }
+    }
+    writeln!(
+        w,
+        ",\"message\":{},\"fields\":{}}}",
+        JsonStr(&message(record, strings)),
+        JsonFields(&record.fields)
+    )
+}
+
+fn write_csv(w: &mut dyn Write, record: &Record, strings: &StringTable) -> io::Result<()> {
+    let wall_time = record.wall_time.map(|t| WallTime(t).to_string());
+    let level = record.level.map(|level| level.to_string());
+    let location = record.location.unwrap_or_default();
+    let name = |hash| (hash != 0).then(|| strings.name(hash));
+    let line = (location.file != 0).then(|| location.line.to_string());
+    let fields = KeyValues(&record.fields).to_string();
+
+    writeln!(
+        w,
+        "{},{},{},{},{},{},{},{},{},{},{}",
+        record.seq,
+        record.boot,
+        record.timestamp,
+        CsvStr(wall_time.as_deref().unwrap_or("")),
+        record.kind.as_str(),
+        CsvStr(level.as_deref().unwrap_or("")),
+        CsvStr(name(location.module).as_deref().unwrap_or("")),
+        CsvStr(name(location.file).as_deref().unwrap_or("")),
+        CsvStr(line.as_deref().unwrap_or("")),
+        CsvStr(&message(record, strings)),
+        CsvStr(fields.trim_start())
+    )
+}
+
+// CSVの値(カンマや改行などを含む場合だけ""で囲む)
+struct CsvStr<'a>(&'a str);
+
+impl fmt::Display for CsvStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.contains([',', '"', '\n', '\r']) {
+            write!(f, "\"{}\"", self.0.replace('"', "\"\""))
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseExportError {
+    what: &'static str,
+    value: String,
+}
+
+impl ParseExportError {
+    fn new(what: &'static str, value: &str) -> Self {
+        ParseExportError {
+            what,
+            value: value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {} '{}'", self.what, self.value)
+    }
+}
+
+impl std::error::Error for ParseExportError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fram_logger::{Field, FieldValue, ResetReason, SourceLocation};
+    use log::Level;
+
+    // 2024-05-01T12:34:56.789Z
+    const WALL_TIME: u64 = 1_714_566_896_789;
+
+    fn record(kind: RecordKind, level: Option<Level>, text: &str) -> Record {
+        Record {
+            kind,
+            level,
+            seq: 7,
+            boot: 3,
+            timestamp: 1500,
+            wall_time: None,
+            location: None,
+            fields: Vec::new(),
+            payload: text.as_bytes().to_vec(),
+        }
+    }
+
+    // モジュール名、ファイル名とkey-valueを持つログ
+    fn full_record(text: &str) -> (Record, StringTable) {
+        let mut strings = StringTable::new();
+        strings.insert(1, "app::motor");
+        strings.insert(2, "src/motor.rs");
+        let mut record = record(RecordKind::Log, Some(Level::Warn), text);
+        record.wall_time = Some(WALL_TIME);
+        record.location = Some(SourceLocation {
+            file: 2,
+            module: 1,
+            line: 42,
+        });
+        record.fields = vec![
+            Field {
+                key: "rpm".to_string(),
+                value: FieldValue::Int(-5),
+            },
+            Field {
+                key: "name".to_string(),
+                value: FieldValue::Str("a \"b\"".to_string()),
+            },
+        ];
+        (record, strings)
+    }
+
+    fn json(record: &Record, strings: &StringTable) -> String {
+        let mut out = Vec::new();
+        write_json(&mut out, record, strings).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn csv(record: &Record, strings: &StringTable) -> String {
+        let mut out = Vec::new();
+        write_csv(&mut out, record, strings).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_json() {
+        let strings = StringTable::new();
+        let (full, full_strings) = full_record("say \"hi\"\\\n\tend\u{1}");
+        let mut boot = record(RecordKind::Boot, None, "");
+        boot.payload = vec![ResetReason::Panic.to_u8()];
+        let mut unknown = record(RecordKind::Log, Some(Level::Info), "x");
+        unknown.location = Some(SourceLocation {
+            file: 0x1234,
+            module: 0,
+            line: 9,
+        });
+
+        let table = [
+            (
+                record(RecordKind::Text, None, "plain"),
+                &strings,
+                r#"{"seq":7,"boot":3,"timestamp":1500,"wall_time":null,"kind":"text","level":null,"message":"plain","fields":{}}"#,
+            ),
+            (
+                full,
+                &full_strings,
+                r#"{"seq":7,"boot":3,"timestamp":1500,"wall_time":"2024-05-01T12:34:56.789Z","kind":"log","level":"WARN","module":"app::motor","file":"src/motor.rs","line":42,"message":"say \"hi\"\\\n\tend\u0001","fields":{"rpm":-5,"name":"a \"b\""}}"#,
+            ),
+            (
+                boot,
+                &strings,
+                r#"{"seq":7,"boot":3,"timestamp":1500,"wall_time":null,"kind":"boot","level":null,"message":"panic","fields":{}}"#,
+            ),
+            // 上書きされた文字列はハッシュで表示する
+            (
+                unknown,
+                &strings,
+                r##"{"seq":7,"boot":3,"timestamp":1500,"wall_time":null,"kind":"log","level":"INFO","file":"#00001234","line":9,"message":"x","fields":{}}"##,
+            ),
+        ];
+        for (record, strings, expected) in table {
+            assert_eq!(json(&record, strings), format!("{}\n", expected));
+        }
+    }
+
+    #[test]
+    fn writes_csv() {
+        let strings = StringTable::new();
+        let (full, full_strings) = full_record("a,b");
+        let table = [
+            (
+                record(RecordKind::Text, None, "plain"),
+                &strings,
+                "7,3,1500,,text,,,,,plain,",
+            ),
+            (
+                full,
+                &full_strings,
+                r#"7,3,1500,2024-05-01T12:34:56.789Z,log,WARN,app::motor,src/motor.rs,42,"a,b","rpm=-5 name=""a \""b\""""""#,
+            ),
+            (
+                record(RecordKind::Log, Some(Level::Info), "say \"hi\""),
+                &strings,
+                r#"7,3,1500,,log,INFO,,,,"say ""hi""","#,
+            ),
+            (
+                record(RecordKind::Log, Some(Level::Info), "two\nlines\r"),
+                &strings,
+                "7,3,1500,,log,INFO,,,,\"two\nlines\r\",",
+            ),
+        ];
+        for (record, strings, expected) in table {
+            assert_eq!(csv(&record, strings), format!("{}\n", expected));
+        }
+
+        // 1行目は列の名前
+        let mut out = Vec::new();
+        ExportFormat::Csv
+            .write(&mut out, &[], &strings, &ExportFilter::new())
+            .unwrap();
+        assert_eq!(out, format!("{}\n", CSV_COLUMNS).as_bytes());
+    }
+
+    #[test]
+    fn filters_records() {
+        let mut log = record(RecordKind::Log, Some(Level::Info), "");
+        log.wall_time = Some(WALL_TIME);
+        let text = record(RecordKind::Text, None, "");
+        let str = record(RecordKind::Str, None, "");
+
+        let filter = |f: fn(&mut ExportFilter)| {
+            let mut filter = ExportFilter::new();
+            f(&mut filter);
+            filter
+        };
+        let table = [
+            (ExportFilter::new(), true, true),
+            (filter(|f| f.level = LevelFilter::Info), true, true),
+            (filter(|f| f.level = LevelFilter::Warn), false, true),
+            (filter(|f| f.level = LevelFilter::Off), false, true),
+            (filter(|f| f.boots = Some(3..=3)), true, true),
+            (filter(|f| f.boots = Some(1..=2)), false, false),
+            (filter(|f| f.boots = Some(4..=u32::MAX)), false, false),
+            (
+                filter(|f| f.from = Some(TimeBound::Uptime(1500))),
+                true,
+                true,
+            ),
+            (
+                filter(|f| f.from = Some(TimeBound::Uptime(1501))),
+                false,
+                false,
+            ),
+            (filter(|f| f.to = Some(TimeBound::Uptime(1500))), true, true),
+            (
+                filter(|f| f.to = Some(TimeBound::Uptime(1499))),
+                false,
+                false,
+            ),
+            // 日時を持たないレコードは除く
+            (
+                filter(|f| f.from = Some(TimeBound::Wall(WALL_TIME))),
+                true,
+                false,
+            ),
+            (
+                filter(|f| f.from = Some(TimeBound::Wall(WALL_TIME + 1))),
+                false,
+                false,
+            ),
+            (
+                filter(|f| f.to = Some(TimeBound::Wall(WALL_TIME))),
+                true,
+                false,
+            ),
+            (
+                filter(|f| f.to = Some(TimeBound::Wall(WALL_TIME - 1))),
+                false,
+                false,
+            ),
+        ];
+        for (i, (filter, matches_log, matches_text)) in table.into_iter().enumerate() {
+            assert_eq!(filter.matches(&log), matches_log, "{}: {:?}", i, filter);
+            assert_eq!(filter.matches(&text), matches_text, "{}: {:?}", i, filter);
+            assert!(!filter.matches(&str), "{}: {:?}", i, filter);
+        }
+    }
+
+    #[test]
+    fn parses_time_bound() {
+        let table = [
+            ("0", Ok(TimeBound::Uptime(0))),
+            ("1500", Ok(TimeBound::Uptime(1500))),
+            ("2024-05-01T12:34:56.789Z", Ok(TimeBound::Wall(WALL_TIME))),
+            ("2024-05-01T12:34:56", Ok(TimeBound::Wall(WALL_TIME - 789))),
+            ("2024-05-01", Ok(TimeBound::Wall(1_714_521_600_000))),
+            ("", Err(ParseExportError::new("time", ""))),
+            ("-1", Err(ParseExportError::new("time", "-1"))),
+            ("soon", Err(ParseExportError::new("time", "soon"))),
+            (
+                "2024-13-01",
+                Err(ParseExportError::new("time", "2024-13-01")),
+            ),
+        ];
+        for (s, expected) in table {
+            assert_eq!(s.parse::<TimeBound>(), expected, "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,426 @@
+// logクレートのkey-value
+//
+// log::info!(target: "motor", rpm = 1200, ok = true; "spin up")
+//
+// ログのレコードのペイロードの先頭に、以下を順に書き込む
+//  キーのbyte数(1byte)、キー(UTF-8)、型(1byte)、値
+//
+//  型  値
+//  0   false(値なし)
+//  1   true(値なし)
+//  2   符号付き整数(ZigZag変換したLEB128)
+//  3   符号なし整数(LEB128)
+//  4   浮動小数点数(f64、8byte)
+//  5   文字列(byte数のLEB128とUTF-8)
+
+use super::FramError;
+use std::fmt;
+
+const BOOL_FALSE: u8 = 0;
+const BOOL_TRUE: u8 = 1;
+const INT: u8 = 2;
+const UINT: u8 = 3;
+const FLOAT: u8 = 4;
+const STR: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Str(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub key: String,
+    pub value: FieldValue,
+}
+
+// 値だけを書き込む(後から値の列を書き込むのにも使う)
+pub fn encode_value(bytes: &mut Vec<u8>, value: &FieldValue) {
+    match value {
+        FieldValue::Bool(false) => bytes.push(BOOL_FALSE),
+        FieldValue::Bool(true) => bytes.push(BOOL_TRUE),
+        FieldValue::Int(v) => {
+            bytes.push(INT);
+            write_varint(bytes, (v << 1 ^ v >> 63) as u64);
+        }
+        FieldValue::Uint(v) => {
+            bytes.push(UINT);
+            write_varint(bytes, *v);
+        }
+        FieldValue::Float(v) => {
+            bytes.push(FLOAT);
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        FieldValue::Str(s) => {
+            bytes.push(STR);
+            write_varint(bytes, s.len() as u64);
+            bytes.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+// bytesの先頭の値を取り出す
+pub fn decode_value(bytes: &mut &[u8]) -> Result<FieldValue, FramError> {
+    let value = match take(bytes, 1)?[0] {
+        BOOL_FALSE => FieldValue::Bool(false),
+        BOOL_TRUE => FieldValue::Bool(true),
+        INT => {
+            let v = read_varint(bytes)?;
+            FieldValue::Int((v >> 1) as i64 ^ -((v & 1) as i64))
+        }
+        UINT => FieldValue::Uint(read_varint(bytes)?),
+        FLOAT => FieldValue::Float(f64::from_le_bytes(take(bytes, 8)?.try_into().unwrap())),
+        STR => {
+            let len = read_varint(bytes)? as usize;
+            FieldValue::Str(String::from_utf8_lossy(take(bytes, len)?).into_owned())
+        }
+        _ => return Err(FramError::Corrupt),
+    };
+    Ok(value)
+}
+
+// 長すぎるキーは切り詰める
+pub fn encode_fields(fields: &[Field]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for field in fields {
+        let mut len = field.key.len().min(u8::MAX as usize);
+        while !field.key.is_char_boundary(len) {
+            len -= 1;
+        }
+        bytes.push(len as u8);
+        bytes.extend_from_slice(&field.key.as_bytes()[..len]);
+        encode_value(&mut bytes, &field.value);
+    }
+    bytes
+}
+
+pub fn decode_fields(bytes: &[u8]) -> Result<Vec<Field>, FramError> {
+    let mut bytes = bytes;
+    let mut fields = Vec::new();
+    while !bytes.is_empty() {
+        let len = take(&mut bytes, 1)?[0] as usize;
+        let key = String::from_utf8_lossy(take(&mut bytes, len)?).into_owned();
+        let value = decode_value(&mut bytes)?;
+        fields.push(Field { key, value });
+    }
+    Ok(fields)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], FramError> {
+    if bytes.len() < len {
+        return Err(FramError::Corrupt);
+    }
+    let (head, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(head)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        bytes.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    bytes.push(v as u8);
+}
+
+// 64bitに収まらないものや、余分な0で長くしたものは壊れているとする
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, FramError> {
+    let mut v: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let b = take(bytes, 1)?[0];
+        if (shift > 0 && b == 0) || (shift == 63 && b > 1) {
+            return Err(FramError::Corrupt);
+        }
+        v |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(FramError::Corrupt)
+}
+
+// logのkey-valueを集める
+pub fn collect(source: &dyn log::kv::Source) -> Vec<Field> {
+    struct Collector(Vec<Field>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Collector {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            self.0.push(Field {
+                key: key.as_str().to_string(),
+                value: FieldValue::from_kv(&value),
+            });
+            Ok(())
+        }
+    }
+
+    let mut collector = Collector(Vec::new());
+    let _ = source.visit(&mut collector);
+    collector.0
+}
+
+impl FieldValue {
+    // 数値、bool、文字列以外は、表示した文字列にする
+    pub fn from_kv(value: &log::kv::Value) -> Self {
+        if let Some(v) = value.to_bool() {
+            FieldValue::Bool(v)
+        } else if let Some(v) = value.to_i64() {
+            FieldValue::Int(v)
+        } else if let Some(v) = value.to_u64() {
+            FieldValue::Uint(v)
+        } else if let Some(v) = value.to_f64() {
+            FieldValue::Float(v)
+        } else if let Some(v) = value.to_borrowed_str() {
+            FieldValue::Str(v.to_string())
+        } else {
+            FieldValue::Str(value.to_string())
+        }
+    }
+}
+
+// key=valueの形式(文字列は空白などを含む場合だけ""で囲む)
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::Bool(v) => write!(f, "{}", v),
+            FieldValue::Int(v) => write!(f, "{}", v),
+            FieldValue::Uint(v) => write!(f, "{}", v),
+            FieldValue::Float(v) => write!(f, "{}", v),
+            FieldValue::Str(s) if s.is_empty() || s.contains([' ', '"', '=']) => {
+                write!(f, "{:?}", s)
+            }
+            FieldValue::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+// " key=value key=value"
+pub struct KeyValues<'a>(pub &'a [Field]);
+
+impl fmt::Display for KeyValues<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for field in self.0 {
+            write!(f, " {}={}", field.key, field.value)?;
+        }
+        Ok(())
+    }
+}
+
+// {"key":value,...}
+pub struct JsonFields<'a>(pub &'a [Field]);
+
+impl fmt::Display for JsonFields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, field) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:", JsonStr(&field.key))?;
+            match &field.value {
+                FieldValue::Str(s) => write!(f, "{}", JsonStr(s))?,
+                // JSONではNaNや無限大を表せない
+                FieldValue::Float(v) if !v.is_finite() => write!(f, "null")?,
+                value => write!(f, "{}", value)?,
+            }
+        }
+        write!(f, "}}")
+    }
+}
+
+// JSONの文字列(エスケープして""で囲む)
+pub struct JsonStr<'a>(pub &'a str);
+
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::kv::Value;
+
+    fn encoded(value: &FieldValue) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        encode_value(&mut bytes, value);
+        bytes
+    }
+
+    fn field(key: &str, value: FieldValue) -> Field {
+        Field {
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn round_trips_values() {
+        let values = [
+            FieldValue::Bool(false),
+            FieldValue::Bool(true),
+            FieldValue::Int(0),
+            FieldValue::Int(-1),
+            FieldValue::Int(63),
+            FieldValue::Int(-64),
+            FieldValue::Int(i64::MIN),
+            FieldValue::Int(i64::MAX),
+            FieldValue::Uint(0),
+            FieldValue::Uint(0x7F),
+            FieldValue::Uint(0x80),
+            FieldValue::Uint(u64::MAX),
+            FieldValue::Float(-0.5),
+            FieldValue::Float(f64::INFINITY),
+            FieldValue::Float(f64::NEG_INFINITY),
+            FieldValue::Str(String::new()),
+            FieldValue::Str("モーター".to_string()),
+            FieldValue::Str("x".repeat(300)),
+        ];
+        for value in values {
+            let bytes = encoded(&value);
+            let mut rest = &bytes[..];
+            assert_eq!(decode_value(&mut rest), Ok(value.clone()), "{:?}", value);
+            assert!(rest.is_empty(), "{:?}", value);
+        }
+
+        let bytes = encoded(&FieldValue::Float(f64::NAN));
+        let Ok(FieldValue::Float(v)) = decode_value(&mut &bytes[..]) else {
+            panic!("not a float");
+        };
+        assert!(v.is_nan());
+    }
+
+    #[test]
+    fn rejects_broken_values() {
+        let table: [&[u8]; 12] = [
+            // 空
+            &[],
+            // 知らない型
+            &[6],
+            // 整数が途中で終わる
+            &[INT],
+            &[UINT, 0x80],
+            &[UINT, 0xFF, 0xFF],
+            // 64bitに収まらない
+            &[
+                UINT, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02,
+            ],
+            &[
+                UINT, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x81, 0x00,
+            ],
+            // 余分な0で長くしたもの
+            &[UINT, 0x80, 0x00],
+            &[INT, 0x81, 0x80, 0x00],
+            // 浮動小数点数が8byteない
+            &[FLOAT, 0, 0, 0, 0, 0, 0, 0],
+            // 文字列が長さに足りない
+            &[STR, 3, b'a', b'b'],
+            &[STR, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, b'a'],
+        ];
+        for bytes in table {
+            assert_eq!(
+                decode_value(&mut &bytes[..]),
+                Err(FramError::Corrupt),
+                "{:02x?}",
+                bytes
+            );
+        }
+        assert_eq!(decode_fields(&[3, b'k', b'e']), Err(FramError::Corrupt));
+        assert_eq!(
+            decode_fields(&[1, b'k', UINT, 0x80]),
+            Err(FramError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn truncates_long_keys() {
+        let fields = [
+            field("rpm", FieldValue::Uint(1200)),
+            field(&"k".repeat(300), FieldValue::Bool(true)),
+            // 255byte目が文字の途中になる
+            field(&format!("k{}", "あ".repeat(100)), FieldValue::Int(-3)),
+        ];
+        let decoded = decode_fields(&encode_fields(&fields)).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0], fields[0]);
+        assert_eq!(decoded[1], field(&"k".repeat(255), FieldValue::Bool(true)));
+        assert_eq!(
+            decoded[2],
+            field(&format!("k{}", "あ".repeat(84)), FieldValue::Int(-3))
+        );
+    }
+
+    #[test]
+    fn formats_json() {
+        let table = [
+            ("plain", "\"plain\""),
+            ("a\"b\\c", "\"a\\\"b\\\\c\""),
+            ("\n\r\t", "\"\\n\\r\\t\""),
+            ("\u{0}\u{1f}\u{7f}", "\"\\u0000\\u001f\u{7f}\""),
+            ("モーター", "\"モーター\""),
+        ];
+        for (s, json) in table {
+            assert_eq!(JsonStr(s).to_string(), json, "{:?}", s);
+        }
+
+        let fields = [
+            field("ok", FieldValue::Bool(true)),
+            field("n", FieldValue::Int(-5)),
+            field("nan", FieldValue::Float(f64::NAN)),
+            field("inf", FieldValue::Float(f64::NEG_INFINITY)),
+            field("x", FieldValue::Float(0.25)),
+            field("s\"", FieldValue::Str("a b\n".to_string())),
+        ];
+        assert_eq!(
+            JsonFields(&fields).to_string(),
+            r#"{"ok":true,"n":-5,"nan":null,"inf":null,"x":0.25,"s\"":"a b\n"}"#
+        );
+        assert_eq!(JsonFields(&[]).to_string(), "{}");
+    }
+
+    #[test]
+    fn collects_key_values() {
+        let kvs = [
+            ("ok", Value::from(false)),
+            ("rpm", Value::from(-1200)),
+            ("big", Value::from(u64::MAX)),
+            ("ratio", Value::from(0.5)),
+            ("name", Value::from("left motor")),
+        ];
+        assert_eq!(
+            collect(&&kvs[..]),
+            [
+                field("ok", FieldValue::Bool(false)),
+                field("rpm", FieldValue::Int(-1200)),
+                field("big", FieldValue::Uint(u64::MAX)),
+                field("ratio", FieldValue::Float(0.5)),
+                field("name", FieldValue::Str("left motor".to_string())),
+            ]
+        );
+        assert_eq!(
+            KeyValues(&collect(&&kvs[..])).to_string(),
+            " ok=false rpm=-1200 big=18446744073709551615 ratio=0.5 name=\"left motor\""
+        );
+        assert!(collect(&()).is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+// ログのレベルのフィルタ
+//
+// env_loggerと同じ書式で、全体のレベルとモジュールごとのレベルを指定する
+//
+// let filter: Filter = "warn,my_app::motor=debug,my_app::ble=off".parse()?;
+//
+// モジュールの指定はtargetの前方一致(::の区切り単位)で、最も長く一致したものを使う
+
+use log::{LevelFilter, Metadata};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    // どのモジュールの指定にも当てはまらないときのレベル
+    level: LevelFilter,
+
+    // モジュールごとのレベル
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub fn new(level: LevelFilter) -> Self {
+        Filter {
+            level,
+            modules: Vec::new(),
+        }
+    }
+
+    pub fn module(mut self, module: &str, level: LevelFilter) -> Self {
+        self.modules.retain(|(m, _)| m != module);
+        self.modules.push((module.to_string(), level));
+        self
+    }
+
+    // targetに適用されるレベル
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    // どこかで通す最も詳しいレベル(log::set_max_levelに使う)
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(LevelFilter::Info)
+    }
+}
+
+// "info,motor=debug"のような文字列から作る
+// モジュール名だけの指定は、そのモジュールのすべてのログを通す
+// 空の文字列は、指定を忘れたものとしてエラーにする
+impl FromStr for Filter {
+    type Err = ParseFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.split(',').all(|d| d.trim().is_empty()) {
+            return Err(ParseFilterError(s.to_string()));
+        }
+        let mut filter = Filter::new(LevelFilter::Off);
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let error = || ParseFilterError(directive.to_string());
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let level = level.trim().parse().map_err(|_| error())?;
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(error());
+                    }
+                    filter = filter.module(module, level);
+                }
+                None => match directive.parse() {
+                    Ok(level) => filter.level = level,
+                    Err(_) => filter = filter.module(directive, LevelFilter::Trace),
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseFilterError(String);
+
+impl fmt::Display for ParseFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid log filter directive '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseFilterError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_directives() {
+        let cases = [
+            ("info", Filter::new(LevelFilter::Info)),
+            (" warn ", Filter::new(LevelFilter::Warn)),
+            (
+                "motor",
+                Filter::new(LevelFilter::Off).module("motor", LevelFilter::Trace),
+            ),
+            (
+                "warn,motor=debug, ble = off",
+                Filter::new(LevelFilter::Warn)
+                    .module("motor", LevelFilter::Debug)
+                    .module("ble", LevelFilter::Off),
+            ),
+            (
+                "motor=info,,motor=error",
+                Filter::new(LevelFilter::Off).module("motor", LevelFilter::Error),
+            ),
+        ];
+        for (spec, expected) in cases {
+            assert_eq!(spec.parse::<Filter>(), Ok(expected), "{spec:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_directives() {
+        let cases = [
+            ("", ""),
+            (" , ", " , "),
+            ("=info", "=info"),
+            ("x=bogus", "x=bogus"),
+            ("info,x=", "x="),
+        ];
+        for (spec, directive) in cases {
+            assert_eq!(
+                spec.parse::<Filter>(),
+                Err(ParseFilterError(directive.to_string())),
+                "{spec:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn uses_longest_module_prefix() {
+        let filter: Filter = "warn,app=info,app::ble=debug,app::ble::scan=off"
+            .parse()
+            .unwrap();
+        let cases = [
+            ("app", LevelFilter::Info),
+            ("app::x", LevelFilter::Info),
+            ("app::ble", LevelFilter::Debug),
+            ("app::ble::gatt", LevelFilter::Debug),
+            ("app::ble::scan", LevelFilter::Off),
+            ("app::ble::scanner", LevelFilter::Debug),
+            ("app2", LevelFilter::Warn),
+            ("app2::x", LevelFilter::Warn),
+            ("ap", LevelFilter::Warn),
+            ("other", LevelFilter::Warn),
+        ];
+        for (target, level) in cases {
+            assert_eq!(filter.level_for(target), level, "{target:?}");
+        }
+    }
+
+    #[test]
+    fn enables_by_target() {
+        let filter: Filter = "warn,app=debug".parse().unwrap();
+        let enabled = |level, target| {
+            filter.enabled(&Metadata::builder().level(level).target(target).build())
+        };
+        assert!(enabled(log::Level::Debug, "app::x"));
+        assert!(!enabled(log::Level::Trace, "app::x"));
+        assert!(enabled(log::Level::Warn, "app2"));
+        assert!(!enabled(log::Level::Info, "app2"));
+    }
+
+    #[test]
+    fn finds_max_level() {
+        let cases = [
+            ("info", LevelFilter::Info),
+            ("off", LevelFilter::Off),
+            ("warn,app=debug", LevelFilter::Debug),
+            ("trace,app=off", LevelFilter::Trace),
+            ("app", LevelFilter::Trace),
+        ];
+        for (spec, level) in cases {
+            assert_eq!(
+                spec.parse::<Filter>().unwrap().max_level(),
+                level,
+                "{spec:?}"
+            );
+        }
+    }
+}
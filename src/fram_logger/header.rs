@@ -0,0 +1,154 @@
+// FRAMの先頭に置くヘッダ
+//
+//  0..4   マジックナンバー "FLOG"
+//  4      フォーマットのバージョン
+//  0x08   スロットA
+//  0x24   スロットB
+//  0x3F   自己診断で書き換えるスクラッチ領域
+//
+// リングバッファの位置は2つのスロットに交互に書き込む
+// 書き込み中に電源が切れても、もう一方のスロットに直前の位置が残る
+//
+// スロット(SLOT_SIZE byte, リトルエンディアン)
+//  0..4   世代(書き込むたびに1増やす。大きい方が新しい)
+//  4      フラグ (bit0: 古いログを上書きしたことがある)
+//  8..12  head: 最も古いログの位置
+//  12..16 tail: 次に書き込む位置
+//  16..20 次のレコードのシーケンス番号
+//  20..24 起動回数
+//  24..26 0..24のCRC-16
+//
+// ログはHEADER_SIZE以降をリングバッファとして使い、レコード(record.rs)を順に書き込む
+// head == tailなら空で、満杯でもtailはheadの1byte手前までしか進めない
+
+use super::crc::crc16;
+use super::{FramDevice, FramError};
+
+pub const HEADER_SIZE: u32 = 0x40;
+pub const SCRATCH_ADRS: u32 = HEADER_SIZE - 1;
+
+const MAGIC: [u8; 4] = *b"FLOG";
+const VERSION: u8 = 5;
+
+const SLOT_ADRS: [u32; 2] = [0x08, 0x24];
+const SLOT_SIZE: usize = 26;
+
+const FLAG_WRAPPED: u8 = 0x01;
+
+#[derive(Clone, Copy)]
+pub struct Header {
+    pub head: u32,
+    pub tail: u32,
+    pub wrapped: bool,
+    pub seq: u32,
+    pub boot: u32,
+
+    // 最後に書き込んだスロットの世代
+    generation: u32,
+}
+
+impl Header {
+    // 空のログ
+    pub fn empty() -> Header {
+        Header {
+            head: HEADER_SIZE,
+            tail: HEADER_SIZE,
+            wrapped: false,
+            seq: 0,
+            boot: 0,
+            generation: 0,
+        }
+    }
+
+    // 空のログとしてフォーマットする
+    // マジックナンバーは最後に書くので、途中で電源が切れても未フォーマットのまま
+    pub fn format(device: &mut dyn FramDevice) -> Result<Header, FramError> {
+        let mut header = Header::empty();
+        device.write(0, &[0; 5])?;
+        for adrs in SLOT_ADRS {
+            device.write(adrs, &[0; SLOT_SIZE])?;
+        }
+        header.store(device)?;
+
+        let mut buffer: [u8; 5] = [0; 5];
+        buffer[0..4].copy_from_slice(&MAGIC);
+        buffer[4] = VERSION;
+        device.write(0, &buffer)?;
+        Ok(header)
+    }
+
+    // FRAMからヘッダを読み込む
+    // フォーマットされていなければNone、どちらのスロットも壊れていればCorruptを返す
+    pub fn load(device: &mut dyn FramDevice) -> Result<Option<Header>, FramError> {
+        let mut buffer: [u8; 5] = [0; 5];
+        device.read(0, &mut buffer)?;
+        if buffer[0..4] != MAGIC || buffer[4] != VERSION {
+            return Ok(None);
+        }
+
+        let mut newest: Option<Header> = None;
+        for adrs in SLOT_ADRS {
+            let Some(header) = Self::load_slot(device, adrs)? else {
+                continue;
+            };
+            // 世代は折り返すので、差で比べる
+            match newest {
+                Some(n) if (header.generation.wrapping_sub(n.generation) as i32) <= 0 => {}
+                _ => newest = Some(header),
+            }
+        }
+        newest.map(Some).ok_or(FramError::Corrupt)
+    }
+
+    // スロットを1つ読み込む(壊れていればNone)
+    fn load_slot(device: &mut dyn FramDevice, adrs: u32) -> Result<Option<Header>, FramError> {
+        let mut buffer: [u8; SLOT_SIZE] = [0; SLOT_SIZE];
+        device.read(adrs, &mut buffer)?;
+
+        let crc = u16::from_le_bytes(buffer[24..26].try_into().unwrap());
+        if crc != crc16(&buffer[..24]) {
+            return Ok(None);
+        }
+
+        let generation = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
+        let head = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        let tail = u32::from_le_bytes(buffer[12..16].try_into().unwrap());
+        let seq = u32::from_le_bytes(buffer[16..20].try_into().unwrap());
+        let boot = u32::from_le_bytes(buffer[20..24].try_into().unwrap());
+        let range = HEADER_SIZE..device.capacity() as u32;
+        if !range.contains(&head) || !range.contains(&tail) {
+            return Ok(None);
+        }
+
+        Ok(Some(Header {
+            head,
+            tail,
+            wrapped: buffer[4] & FLAG_WRAPPED != 0,
+            seq,
+            boot,
+            generation,
+        }))
+    }
+
+    // 位置を確定する
+    // 古い方のスロットに書き込むので、書き込みが途中で止まっても直前の位置が残る
+    pub fn store(&mut self, device: &mut dyn FramDevice) -> Result<(), FramError> {
+        let generation = self.generation.wrapping_add(1);
+
+        let mut buffer: [u8; SLOT_SIZE] = [0; SLOT_SIZE];
+        buffer[0..4].copy_from_slice(&generation.to_le_bytes());
+        if self.wrapped {
+            buffer[4] |= FLAG_WRAPPED;
+        }
+        buffer[8..12].copy_from_slice(&self.head.to_le_bytes());
+        buffer[12..16].copy_from_slice(&self.tail.to_le_bytes());
+        buffer[16..20].copy_from_slice(&self.seq.to_le_bytes());
+        buffer[20..24].copy_from_slice(&self.boot.to_le_bytes());
+        let crc = crc16(&buffer[..24]);
+        buffer[24..26].copy_from_slice(&crc.to_le_bytes());
+
+        device.write(SLOT_ADRS[generation as usize % 2], &buffer)?;
+        self.generation = generation;
+        Ok(())
+    }
+}
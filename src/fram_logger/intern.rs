@@ -0,0 +1,92 @@
+// ファイル名やモジュール名の短縮
+//
+// レコードには文字列のハッシュだけを書き込み、文字列そのものは
+// 起動ごとに最初に使ったときだけ、文字列のレコードとして書き込む
+//
+// 文字列のレコードのペイロード
+//  0..4   ハッシュ(リトルエンディアン)
+//  4..    UTF-8の文字列
+
+use super::deferred;
+use super::record::{Record, RecordKind};
+use std::collections::HashMap;
+
+// FNV-1a(32bit)
+// 0はハッシュなしを表すので、0になった場合は1にする
+pub const fn fnv1a(s: &str) -> u32 {
+    let bytes = s.as_bytes();
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    if hash == 0 {
+        1
+    } else {
+        hash
+    }
+}
+
+// 文字列のレコードのペイロード
+pub fn encode_string(hash: u32, s: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(4 + s.len());
+    payload.extend_from_slice(&hash.to_le_bytes());
+    payload.extend_from_slice(s.as_bytes());
+    payload
+}
+
+// ハッシュから文字列を引く表
+#[derive(Clone, Debug, Default)]
+pub struct StringTable {
+    strings: HashMap<u32, String>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 文字列のレコードを集める
+    pub fn from_records<'a>(records: impl IntoIterator<Item = &'a Record>) -> Self {
+        let mut table = Self::new();
+        for record in records {
+            table.add(record);
+        }
+        table
+    }
+
+    // 文字列のレコードなら登録する
+    pub fn add(&mut self, record: &Record) {
+        if record.kind != RecordKind::Str || record.payload.len() < 4 {
+            return;
+        }
+        let hash = u32::from_le_bytes(record.payload[0..4].try_into().unwrap());
+        let s = String::from_utf8_lossy(&record.payload[4..]).into_owned();
+        self.strings.insert(hash, s);
+    }
+
+    // バイナリ(ELFファイルなど)に埋め込まれた書式文字列を登録する(deferred.rs)
+    pub fn add_formats(&mut self, bytes: &[u8]) {
+        for (id, fmt) in deferred::scan(bytes) {
+            self.insert(id, fmt);
+        }
+    }
+
+    pub fn insert(&mut self, hash: u32, s: &str) {
+        self.strings.insert(hash, s.to_string());
+    }
+
+    pub fn get(&self, hash: u32) -> Option<&str> {
+        self.strings.get(&hash).map(String::as_str)
+    }
+
+    // 文字列が見つからなければ(上書きされていれば)ハッシュを返す
+    pub fn name(&self, hash: u32) -> String {
+        match self.get(hash) {
+            Some(s) => s.to_string(),
+            None => format!("#{:08x}", hash),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// FRAMの型番ごとの仕様
+//
+// メモリアドレスのbyte数で表せない上位ビットは、I2Cスレーブアドレスの
+// 下位ビット(A0〜A2の位置)に入れる型番がある (MB85RC04, MB85RC16, MB85RC1M)
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramPart {
+    // 容量(byte)
+    pub capacity: u32,
+
+    // メモリアドレスのbyte数(1または2)
+    pub address_bytes: u8,
+
+    // 1回のI2C転送で書き込むデータのbyte数
+    pub chunk_size: usize,
+
+    // 1回のI2C転送で読み込むデータのbyte数
+    pub read_chunk_size: usize,
+
+    // I2Cスレーブアドレス(上位ビットを入れる位置は0にしておく)
+    pub i2c_address: u8,
+}
+
+// I2C転送1回分のデータの上限
+pub const MAX_CHUNK_SIZE: usize = 64;
+
+impl FramPart {
+    // 4Kbit
+    pub const MB85RC04: FramPart = FramPart::new(0x200, 1);
+    // 16Kbit
+    pub const MB85RC16: FramPart = FramPart::new(0x800, 1);
+    // 64Kbit
+    pub const MB85RC64: FramPart = FramPart::new(0x2000, 2);
+    // 256Kbit
+    pub const MB85RC256: FramPart = FramPart::new(0x8000, 2);
+    // 512Kbit
+    pub const MB85RC512: FramPart = FramPart::new(0x10000, 2);
+    // 1Mbit
+    pub const MB85RC1M: FramPart = FramPart::new(0x20000, 2);
+
+    pub const fn new(capacity: u32, address_bytes: u8) -> Self {
+        FramPart {
+            capacity,
+            address_bytes,
+            chunk_size: 30,
+            read_chunk_size: 128,
+            i2c_address: 0x50,
+        }
+    }
+
+    pub const fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0 && chunk_size <= MAX_CHUNK_SIZE);
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub const fn read_chunk_size(mut self, read_chunk_size: usize) -> Self {
+        assert!(read_chunk_size > 0);
+        self.read_chunk_size = read_chunk_size;
+        self
+    }
+
+    pub const fn i2c_address(mut self, i2c_address: u8) -> Self {
+        self.i2c_address = i2c_address;
+        self
+    }
+
+    // 容量を変えた仕様(アドレスのbyte数も容量に合わせる)
+    // スレーブアドレスに入れられる上位ビットは3bitまでなので、512KiBまで
+    pub fn with_capacity(self, capacity: u32) -> Option<FramPart> {
+        if !capacity.is_power_of_two() || !(0x100..=0x80000).contains(&capacity) {
+            return None;
+        }
+        let address_bytes = if capacity <= 0x800 { 1 } else { 2 };
+        Some(FramPart {
+            capacity,
+            address_bytes,
+            ..self
+        })
+    }
+
+    // メモリアドレスで表せる範囲(この境界をまたいで転送しない)
+    pub fn page_size(&self) -> u32 {
+        1 << (8 * self.address_bytes as u32)
+    }
+
+    // スレーブアドレスに入れる上位ビットの数
+    pub fn page_bits(&self) -> u32 {
+        let bits = 32 - self.capacity.saturating_sub(1).leading_zeros();
+        bits.saturating_sub(8 * self.address_bytes as u32)
+    }
+
+    // adrsにアクセスするときのスレーブアドレス
+    pub fn slave_address(&self, adrs: u32) -> u8 {
+        self.i2c_address | (adrs / self.page_size()) as u8
+    }
+
+    // メモリアドレスをビッグエンディアンでbufferに書き込み、そのbyte数を返す
+    pub fn encode_address(&self, adrs: u32, buffer: &mut [u8]) -> usize {
+        let n = self.address_bytes as usize;
+        for (i, b) in buffer[..n].iter_mut().enumerate() {
+            *b = (adrs >> (8 * (n - 1 - i))) as u8;
+        }
+        n
+    }
+}
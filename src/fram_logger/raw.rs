@@ -0,0 +1,414 @@
+// FRAMの中身をそのままシリアルに出力するための形式
+//
+// FRAM-DUMP BEGIN size=8192
+// :00000000 464C4F4705000000... 1A2B
+// :00000020 ...
+// FRAM-DUMP END crc=3C4D
+//
+// データの行は ":アドレス(8桁) データ(最大LINE_BYTES byte) CRC(4桁)" (16進数)
+// 行のCRCはアドレス(4byte、リトルエンディアン)とデータに対するCRC-16
+// 最後の行のCRCは、FRAM全体に対するCRC-16
+//
+// 読み込むときは、BEGINより前の行や途中に混ざったログなど、形式の違う行は無視する
+// BEGINが複数あれば、最後のものから読み直す
+
+use super::crc::{crc16, crc16_update};
+use std::convert::Infallible;
+use std::fmt::{self, Write};
+
+pub const LINE_BYTES: usize = 32;
+
+const BEGIN: &str = "FRAM-DUMP BEGIN";
+const END: &str = "FRAM-DUMP END";
+
+// 扱えるFRAMの最大の容量(FramPart::with_capacity)
+// これより大きいsizeのBEGINは、壊れた行として無視する
+const MAX_SIZE: usize = 0x80000;
+
+// 出力する行を作る
+pub struct RawEncoder {
+    size: usize,
+    crc: u16,
+}
+
+impl RawEncoder {
+    pub fn new(size: usize) -> Self {
+        RawEncoder { size, crc: 0xFFFF }
+    }
+
+    pub fn begin(&self) -> String {
+        format!("{} size={}", BEGIN, self.size)
+    }
+
+    // adrsからのデータの行(dataはLINE_BYTES byte以下)
+    pub fn line(&mut self, adrs: u32, data: &[u8]) -> String {
+        self.crc = crc16_update(self.crc, data);
+
+        let mut line = String::with_capacity(16 + data.len() * 2);
+        let _ = write!(line, ":{:08X} ", adrs);
+        for b in data {
+            let _ = write!(line, "{:02X}", b);
+        }
+        let _ = write!(line, " {:04X}", line_crc(adrs, data));
+        line
+    }
+
+    pub fn end(&self) -> String {
+        format!("{} crc={:04X}", END, self.crc)
+    }
+
+    // size byteをreadでLINE_BYTESずつ読み出しながら、行を順にemitに渡す
+    pub fn stream<E>(
+        size: usize,
+        mut read: impl FnMut(u32, &mut [u8]) -> Result<(), E>,
+        mut emit: impl FnMut(&str),
+    ) -> Result<(), E> {
+        let mut encoder = RawEncoder::new(size);
+        emit(&encoder.begin());
+        let mut buffer = [0; LINE_BYTES];
+        for adrs in (0..size).step_by(LINE_BYTES) {
+            let data = &mut buffer[..LINE_BYTES.min(size - adrs)];
+            read(adrs as u32, data)?;
+            emit(&encoder.line(adrs as u32, data));
+        }
+        emit(&encoder.end());
+        Ok(())
+    }
+
+    // イメージ全体の行
+    pub fn encode(image: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let read = |adrs: u32, data: &mut [u8]| {
+            let start = adrs as usize;
+            data.copy_from_slice(&image[start..start + data.len()]);
+            Ok::<_, Infallible>(())
+        };
+        RawEncoder::stream(image.len(), read, |line| lines.push(line.to_string()))
+            .unwrap_or_else(|e| match e {});
+        lines
+    }
+}
+
+fn line_crc(adrs: u32, data: &[u8]) -> u16 {
+    crc16_update(crc16(&adrs.to_le_bytes()), data)
+}
+
+// 行からイメージを組み立てる
+#[derive(Default)]
+pub struct RawDecoder {
+    image: Vec<u8>,
+    // 受け取ったbyte
+    received: Vec<bool>,
+    bad_lines: usize,
+    crc: Option<u16>,
+    begun: bool,
+}
+
+impl RawDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 1行ずつ渡す
+    pub fn push_line(&mut self, line: &str) {
+        let line = line.trim();
+        if let Some(size) = line.strip_prefix(BEGIN) {
+            let size = size
+                .trim()
+                .strip_prefix("size=")
+                .and_then(|s| s.parse().ok())
+                .filter(|&size| size <= MAX_SIZE);
+            *self = RawDecoder::new();
+            if let Some(size) = size {
+                self.image = vec![0; size];
+                self.received = vec![false; size];
+                self.begun = true;
+            }
+            return;
+        }
+        if !self.begun || self.crc.is_some() {
+            return;
+        }
+        if let Some(crc) = line.strip_prefix(END) {
+            let crc = crc.trim().strip_prefix("crc=");
+            self.crc = Some(
+                crc.and_then(|s| u16::from_str_radix(s, 16).ok())
+                    .unwrap_or(0),
+            );
+            return;
+        }
+        if line.starts_with(':') && self.push_data(line).is_none() {
+            self.bad_lines += 1;
+        }
+    }
+
+    fn push_data(&mut self, line: &str) -> Option<()> {
+        let mut parts = line[1..].split_whitespace();
+        let adrs = u32::from_str_radix(parts.next()?, 16).ok()?;
+        let data = parts.next()?;
+        let crc = u16::from_str_radix(parts.next()?, 16).ok()?;
+        if parts.next().is_some() || data.len() % 2 != 0 || data.len() > LINE_BYTES * 2 {
+            return None;
+        }
+
+        let data: Vec<u8> = (0..data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+            .collect::<Option<_>>()?;
+        if crc != line_crc(adrs, &data) {
+            return None;
+        }
+
+        let start = adrs as usize;
+        let range = start..start.checked_add(data.len())?;
+        self.image.get_mut(range.clone())?.copy_from_slice(&data);
+        self.received[range].fill(true);
+        Some(())
+    }
+
+    // すべての行を受け取っていれば、イメージを返す
+    pub fn finish(self) -> Result<Vec<u8>, RawError> {
+        if !self.begun {
+            return Err(RawError::NotFound);
+        }
+        let Some(crc) = self.crc else {
+            return Err(RawError::Incomplete);
+        };
+        if self.bad_lines > 0 {
+            return Err(RawError::BadLines(self.bad_lines));
+        }
+        if let Some(adrs) = self.received.iter().position(|&received| !received) {
+            return Err(RawError::Missing(adrs as u32));
+        }
+        if crc != crc16(&self.image) {
+            return Err(RawError::Checksum);
+        }
+        Ok(self.image)
+    }
+
+    // シリアルから取り込んだテキスト全体からイメージを組み立てる
+    pub fn decode(text: &str) -> Result<Vec<u8>, RawError> {
+        let mut decoder = RawDecoder::new();
+        for line in text.lines() {
+            decoder.push_line(line);
+        }
+        decoder.finish()
+    }
+
+    // dump_rawの出力を含んでいるか(BEGINで始まる行があるか)
+    pub fn is_capture(bytes: &[u8]) -> bool {
+        bytes.split(|&b| b == b'\n').any(|line| {
+            let start = line
+                .iter()
+                .position(|b| !b.is_ascii_whitespace())
+                .unwrap_or(line.len());
+            line[start..].starts_with(BEGIN.as_bytes())
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RawError {
+    // BEGINの行がない
+    NotFound,
+    // ENDの行がない(途中で途切れた)
+    Incomplete,
+    // 形式やCRCが正しくない行があった
+    BadLines(usize),
+    // 受け取っていないアドレスがある
+    Missing(u32),
+    // 全体のCRCが一致しない
+    Checksum,
+}
+
+impl fmt::Display for RawError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RawError::NotFound => write!(f, "no FRAM dump found"),
+            RawError::Incomplete => write!(f, "FRAM dump is incomplete"),
+            RawError::BadLines(n) => write!(f, "{} corrupt line(s) in FRAM dump", n),
+            RawError::Missing(adrs) => write!(f, "FRAM dump is missing data at {:#x}", adrs),
+            RawError::Checksum => write!(f, "FRAM dump checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for RawError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        for len in [1, LINE_BYTES - 1, LINE_BYTES, LINE_BYTES + 1, 0x200] {
+            let image = image(len);
+            let lines = RawEncoder::encode(&image);
+            assert_eq!(lines.len(), (len + LINE_BYTES - 1) / LINE_BYTES + 2);
+            assert_eq!(lines[0], format!("FRAM-DUMP BEGIN size={}", len));
+            assert!(RawDecoder::is_capture(lines.join("\n").as_bytes()));
+            assert_eq!(RawDecoder::decode(&lines.join("\n")), Ok(image));
+        }
+    }
+
+    #[test]
+    fn streams_same_lines() {
+        let image = image(0x100);
+        let mut reads = Vec::new();
+        let mut lines = Vec::new();
+        RawEncoder::stream(
+            image.len(),
+            |adrs, data| {
+                reads.push((adrs, data.len()));
+                data.copy_from_slice(&image[adrs as usize..adrs as usize + data.len()]);
+                Ok::<_, ()>(())
+            },
+            |line| lines.push(line.to_string()),
+        )
+        .unwrap();
+        assert_eq!(lines, RawEncoder::encode(&image));
+        assert!(reads.iter().all(|&(_, len)| len == LINE_BYTES));
+        assert_eq!(reads.len(), 0x100 / LINE_BYTES);
+
+        // 読み出しのエラーで止める
+        let mut lines = 0;
+        let result = RawEncoder::stream(
+            0x100,
+            |adrs, _| if adrs < 0x40 { Ok(()) } else { Err(adrs) },
+            |_| lines += 1,
+        );
+        assert_eq!(result, Err(0x40));
+        assert_eq!(lines, 3);
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        // BEGINの前や途中に混ざったログ
+        let image = image(0x80);
+        let mut text = String::from("I (312) boot: ESP-IDF\n:not a dump line\n");
+        for (i, line) in RawEncoder::encode(&image).iter().enumerate() {
+            text.push_str(&format!("\r{}\r\n", line));
+            if i == 2 {
+                text.push_str("[1:    1500] INFO - logging during dump\n\n");
+            }
+        }
+        text.push_str("after the dump\n");
+        assert_eq!(RawDecoder::decode(&text), Ok(image));
+    }
+
+    #[test]
+    fn rejects_bad_line() {
+        let mut lines = RawEncoder::encode(&image(0x80));
+        // データを1文字変える
+        let line = &mut lines[2];
+        let c = if line.as_bytes()[12] == b'0' {
+            "1"
+        } else {
+            "0"
+        };
+        line.replace_range(12..13, c);
+        assert_eq!(
+            RawDecoder::decode(&lines.join("\n")),
+            Err(RawError::BadLines(1))
+        );
+
+        let mut lines = RawEncoder::encode(&image(0x80));
+        lines[3].push_str(" 00");
+        assert_eq!(
+            RawDecoder::decode(&lines.join("\n")),
+            Err(RawError::BadLines(1))
+        );
+    }
+
+    #[test]
+    fn rejects_missing_line() {
+        let mut lines = RawEncoder::encode(&image(0x80));
+        lines.remove(3);
+        assert_eq!(
+            RawDecoder::decode(&lines.join("\n")),
+            Err(RawError::Missing(2 * LINE_BYTES as u32))
+        );
+    }
+
+    #[test]
+    fn restarts_at_last_begin() {
+        // 途中で切れたダンプの後に、もう一度ダンプした
+        let old = RawEncoder::encode(&image(0x100));
+        let new_image: Vec<u8> = image(0x80).iter().map(|b| !b).collect();
+        let new = RawEncoder::encode(&new_image);
+        let text = [&old[..4], &new[..]].concat().join("\n");
+        assert_eq!(RawDecoder::decode(&text), Ok(new_image));
+    }
+
+    #[test]
+    fn ignores_bad_begin() {
+        let lines = RawEncoder::encode(&image(0x80));
+        let data = lines[1..].join("\n");
+        for begin in [
+            "FRAM-DUMP BEGIN",
+            "FRAM-DUMP BEGIN size=",
+            "FRAM-DUMP BEGIN size=-1",
+            "FRAM-DUMP BEGIN size=0x80",
+            "FRAM-DUMP BEGIN size=524289",
+            "FRAM-DUMP BEGIN size=18446744073709551615",
+        ] {
+            let text = format!("{}\n{}", begin, data);
+            assert_eq!(
+                RawDecoder::decode(&text),
+                Err(RawError::NotFound),
+                "{}",
+                begin
+            );
+        }
+
+        // 最大の容量までは受け付ける
+        let mut decoder = RawDecoder::new();
+        decoder.push_line(&RawEncoder::new(MAX_SIZE).begin());
+        assert_eq!(decoder.finish(), Err(RawError::Incomplete));
+    }
+
+    #[test]
+    fn detects_capture_at_line_start() {
+        let table: [(&[u8], bool); 8] = [
+            (b"FRAM-DUMP BEGIN size=128", true),
+            (b"I (312) boot\nFRAM-DUMP BEGIN size=128\n", true),
+            (b"log\r\n\rFRAM-DUMP BEGIN size=128\r\n", true),
+            (b"log\n  FRAM-DUMP BEGIN", true),
+            (b"", false),
+            (b"no dump here\n", false),
+            (b"INFO - FRAM-DUMP BEGIN size=128\n", false),
+            (b"\x00\x01FRAM-DUMP BEGIN size=128", false),
+        ];
+        for (bytes, expected) in table {
+            assert_eq!(
+                RawDecoder::is_capture(bytes),
+                expected,
+                "{:?}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn reports_incomplete_dump() {
+        let lines = RawEncoder::encode(&image(0x80));
+        assert_eq!(RawDecoder::decode("no dump here"), Err(RawError::NotFound));
+        assert_eq!(
+            RawDecoder::decode(&lines[..lines.len() - 1].join("\n")),
+            Err(RawError::Incomplete)
+        );
+
+        // 全体のCRCが合わない
+        let mut lines = lines;
+        let end = lines.len() - 1;
+        lines[end] = "FRAM-DUMP END crc=0000".to_string();
+        assert_eq!(
+            RawDecoder::decode(&lines.join("\n")),
+            Err(RawError::Checksum)
+        );
+    }
+}
@@ -0,0 +1,560 @@
+// ログのレコード
+//
+// リングバッファには、以下のレコードを順に書き込む(リトルエンディアン)
+//
+//  0       同期バイト 0xA5
+//  1       種類 (0: テキスト, 1: ログ, 2: 起動, 3: 文字列, 4: 書式化前のログ)
+//  2       レベル (ログのみ。1: Error 〜 5: Trace、それ以外は0)
+//  3       フラグ (bit0: 現在時刻あり, bit1: 場所あり, bit2: key-valueあり)
+//  4..6    ペイロードのbyte数
+//  6..10   シーケンス番号
+//  10..14  起動回数
+//  14..18  タイムスタンプ(起動からのms)
+//  18..20  CRC-16/CCITT-FALSE (0..18と、それ以降のレコードの終わりまでに対して計算)
+//  ..      現在時刻(UNIX時間のms、8byte。フラグのbit0が立っているときのみ)
+//  ..      ログを書き込んだ場所(12byte。フラグのbit1が立っているときのみ)
+//            0..4 ファイル名のハッシュ, 4..8 モジュール名のハッシュ, 8..12 行番号
+//  ..      ペイロード(テキストとログはUTF-8の文字列、起動はリセットの原因1byte、
+//          文字列はintern.rsを参照、書式化前のログは書式文字列のID(4byte)と
+//          引数の値(fields.rsの値の形式)の列)
+//          key-valueがあれば、ペイロードの先頭にbyte数(2byte)とkey-value(fields.rs)を置く
+
+use super::clock::WallTime;
+use super::crc::{crc16, crc16_update};
+use super::deferred;
+use super::fields::{decode_fields, decode_value, encode_fields, Field, FieldValue, KeyValues};
+use super::intern::StringTable;
+use super::reset::ResetReason;
+use super::FramError;
+use log::Level;
+use std::fmt;
+
+pub const SYNC: u8 = 0xA5;
+pub const RECORD_HEADER_SIZE: usize = 20;
+
+const FLAG_WALL_TIME: u8 = 0x01;
+const FLAG_LOCATION: u8 = 0x02;
+const FLAG_FIELDS: u8 = 0x04;
+const WALL_TIME_SIZE: usize = 8;
+const LOCATION_SIZE: usize = 12;
+
+// 現在時刻と場所を合わせたbyte数(ヘッダに続く部分の最大)
+pub const MAX_EXTENSION_SIZE: usize = WALL_TIME_SIZE + LOCATION_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordKind {
+    // fprint!で書き込んだテキスト
+    Text,
+    // logクレートのログ
+    Log,
+    // 起動の区切り
+    Boot,
+    // ファイル名などの文字列とそのハッシュ
+    Str,
+    // flog!で書き込んだ、書式化前のログ
+    Deferred,
+}
+
+impl RecordKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            RecordKind::Text => 0,
+            RecordKind::Log => 1,
+            RecordKind::Boot => 2,
+            RecordKind::Str => 3,
+            RecordKind::Deferred => 4,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RecordKind::Text => "text",
+            RecordKind::Log => "log",
+            RecordKind::Boot => "boot",
+            RecordKind::Str => "str",
+            RecordKind::Deferred => "deferred",
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(RecordKind::Text),
+            1 => Some(RecordKind::Log),
+            2 => Some(RecordKind::Boot),
+            3 => Some(RecordKind::Str),
+            4 => Some(RecordKind::Deferred),
+            _ => None,
+        }
+    }
+}
+
+fn level_to_u8(level: Option<Level>) -> u8 {
+    level.map_or(0, |level| level as u8)
+}
+
+fn level_from_u8(v: u8) -> Result<Option<Level>, FramError> {
+    match v {
+        0 => Ok(None),
+        1 => Ok(Some(Level::Error)),
+        2 => Ok(Some(Level::Warn)),
+        3 => Ok(Some(Level::Info)),
+        4 => Ok(Some(Level::Debug)),
+        5 => Ok(Some(Level::Trace)),
+        _ => Err(FramError::Corrupt),
+    }
+}
+
+// ログを書き込んだ場所
+// ファイル名とモジュール名はハッシュで持つ(0は不明)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: u32,
+    pub module: u32,
+    pub line: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub kind: RecordKind,
+    pub level: Option<Level>,
+    pub seq: u32,
+    pub boot: u32,
+    // 起動からのms
+    pub timestamp: u32,
+    // UNIX時間のms
+    pub wall_time: Option<u64>,
+    pub location: Option<SourceLocation>,
+    pub fields: Vec<Field>,
+    pub payload: Vec<u8>,
+}
+
+// レコードのヘッダ部分
+pub struct RecordHeader {
+    pub kind: RecordKind,
+    pub level: Option<Level>,
+    pub flags: u8,
+    pub len: usize,
+    pub seq: u32,
+    pub boot: u32,
+    pub timestamp: u32,
+    pub crc: u16,
+}
+
+impl RecordHeader {
+    pub fn decode(bytes: &[u8; RECORD_HEADER_SIZE]) -> Result<Self, FramError> {
+        if bytes[0] != SYNC {
+            return Err(FramError::Corrupt);
+        }
+        Ok(RecordHeader {
+            kind: RecordKind::from_u8(bytes[1]).ok_or(FramError::Corrupt)?,
+            level: level_from_u8(bytes[2])?,
+            flags: bytes[3],
+            len: u16::from_le_bytes([bytes[4], bytes[5]]) as usize,
+            seq: u32::from_le_bytes(bytes[6..10].try_into().unwrap()),
+            boot: u32::from_le_bytes(bytes[10..14].try_into().unwrap()),
+            timestamp: u32::from_le_bytes(bytes[14..18].try_into().unwrap()),
+            crc: u16::from_le_bytes([bytes[18], bytes[19]]),
+        })
+    }
+
+    fn extension_size(&self) -> usize {
+        let mut size = 0;
+        if self.flags & FLAG_WALL_TIME != 0 {
+            size += WALL_TIME_SIZE;
+        }
+        if self.flags & FLAG_LOCATION != 0 {
+            size += LOCATION_SIZE;
+        }
+        size
+    }
+
+    // ヘッダを含めたレコード全体のbyte数
+    pub fn record_size(&self) -> usize {
+        RECORD_HEADER_SIZE + self.extension_size() + self.len
+    }
+}
+
+impl Record {
+    // ペイロードの最大byte数
+    pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.wall_time.is_some() {
+            flags |= FLAG_WALL_TIME;
+        }
+        if self.location.is_some() {
+            flags |= FLAG_LOCATION;
+        }
+        if !self.fields.is_empty() {
+            flags |= FLAG_FIELDS;
+        }
+        flags
+    }
+
+    // ペイロードとして書き込むbyte列(key-valueとテキスト)
+    fn body(&self) -> Vec<u8> {
+        if self.fields.is_empty() {
+            return self.payload.clone();
+        }
+        let fields = encode_fields(&self.fields);
+        let mut body = Vec::with_capacity(2 + fields.len() + self.payload.len());
+        body.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+        body.extend_from_slice(&fields);
+        body.extend_from_slice(&self.payload);
+        body
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        let mut size = RECORD_HEADER_SIZE + self.body().len();
+        if self.wall_time.is_some() {
+            size += WALL_TIME_SIZE;
+        }
+        if self.location.is_some() {
+            size += LOCATION_SIZE;
+        }
+        size
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let body = self.body();
+        assert!(body.len() <= Self::MAX_PAYLOAD);
+
+        let mut bytes = Vec::with_capacity(self.encoded_size());
+        bytes.push(SYNC);
+        bytes.push(self.kind.to_u8());
+        bytes.push(level_to_u8(self.level));
+        bytes.push(self.flags());
+        bytes.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.seq.to_le_bytes());
+        bytes.extend_from_slice(&self.boot.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&[0; 2]);
+        if let Some(wall_time) = self.wall_time {
+            bytes.extend_from_slice(&wall_time.to_le_bytes());
+        }
+        if let Some(location) = self.location {
+            bytes.extend_from_slice(&location.file.to_le_bytes());
+            bytes.extend_from_slice(&location.module.to_le_bytes());
+            bytes.extend_from_slice(&location.line.to_le_bytes());
+        }
+        bytes.extend_from_slice(&body);
+
+        let crc = crc16_update(
+            crc16(&bytes[..RECORD_HEADER_SIZE - 2]),
+            &bytes[RECORD_HEADER_SIZE..],
+        );
+        bytes[RECORD_HEADER_SIZE - 2..RECORD_HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    // bytesの先頭のレコードを取り出し、レコードとそのbyte数を返す
+    pub fn decode(bytes: &[u8]) -> Result<(Record, usize), FramError> {
+        let header: &[u8; RECORD_HEADER_SIZE] = bytes
+            .get(..RECORD_HEADER_SIZE)
+            .ok_or(FramError::Corrupt)?
+            .try_into()
+            .unwrap();
+        let header = RecordHeader::decode(header)?;
+        let body = bytes
+            .get(RECORD_HEADER_SIZE..header.record_size())
+            .ok_or(FramError::Corrupt)?;
+
+        let crc = crc16_update(crc16(&bytes[..RECORD_HEADER_SIZE - 2]), body);
+        if crc != header.crc {
+            return Err(FramError::Corrupt);
+        }
+
+        let (mut extension, mut payload) = body.split_at(header.extension_size());
+        let mut wall_time = None;
+        if header.flags & FLAG_WALL_TIME != 0 {
+            let (bytes, rest) = extension.split_at(WALL_TIME_SIZE);
+            wall_time = Some(u64::from_le_bytes(bytes.try_into().unwrap()));
+            extension = rest;
+        }
+        let mut location = None;
+        if header.flags & FLAG_LOCATION != 0 {
+            let u32_at = |i: usize| u32::from_le_bytes(extension[i..i + 4].try_into().unwrap());
+            location = Some(SourceLocation {
+                file: u32_at(0),
+                module: u32_at(4),
+                line: u32_at(8),
+            });
+        }
+        let mut fields = Vec::new();
+        if header.flags & FLAG_FIELDS != 0 {
+            let len = u16::from_le_bytes(
+                payload
+                    .get(..2)
+                    .ok_or(FramError::Corrupt)?
+                    .try_into()
+                    .unwrap(),
+            );
+            let bytes = payload.get(2..2 + len as usize).ok_or(FramError::Corrupt)?;
+            fields = decode_fields(bytes)?;
+            payload = &payload[2 + len as usize..];
+        }
+
+        let record = Record {
+            kind: header.kind,
+            level: header.level,
+            seq: header.seq,
+            boot: header.boot,
+            timestamp: header.timestamp,
+            wall_time,
+            location,
+            fields,
+            payload: payload.to_vec(),
+        };
+        Ok((record, header.record_size()))
+    }
+
+    // ペイロードを文字列として返す
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.payload)
+    }
+
+    // 起動のレコードなら、リセットの原因を返す
+    pub fn reset_reason(&self) -> Option<ResetReason> {
+        match self.kind {
+            RecordKind::Boot => Some(ResetReason::from_u8(*self.payload.first()?)),
+            _ => None,
+        }
+    }
+
+    // 書式化前のログなら、書式文字列のIDと引数を返す
+    pub fn deferred(&self) -> Option<(u32, Vec<FieldValue>)> {
+        if self.kind != RecordKind::Deferred {
+            return None;
+        }
+        let id = u32::from_le_bytes(self.payload.get(..4)?.try_into().unwrap());
+        let mut bytes = &self.payload[4..];
+        let mut args = Vec::new();
+        while !bytes.is_empty() {
+            args.push(decode_value(&mut bytes).ok()?);
+        }
+        Some((id, args))
+    }
+
+    // ログのテキスト
+    // 書式化前のログは、書式文字列が見つからなければIDと引数を返す
+    pub fn message(&self, strings: &StringTable) -> String {
+        let Some((id, args)) = self.deferred() else {
+            return self.text().into_owned();
+        };
+        match strings.get(id) {
+            Some(fmt) => deferred::render(fmt, &args),
+            None => {
+                let args: String = args.iter().map(|arg| format!(" {}", arg)).collect();
+                format!("<format #{:08x}>{}", id, args)
+            }
+        }
+    }
+
+    // ファイル名とモジュール名をstringsから引いて表示する
+    pub fn display<'a>(&'a self, strings: &'a StringTable) -> RecordDisplay<'a> {
+        RecordDisplay {
+            record: self,
+            strings,
+        }
+    }
+}
+
+// bytesの中で、次の正しいレコードが始まる位置
+// 書き込み途中で電源が切れたレコードや、壊れたレコードを読み飛ばすのに使う
+pub fn resync(bytes: &[u8]) -> usize {
+    (1..bytes.len())
+        .find(|&i| bytes[i] == SYNC && Record::decode(&bytes[i..]).is_ok())
+        .unwrap_or(bytes.len())
+}
+
+// レコードを古い順に取り出す
+// 壊れたレコードがあればエラーを1つ返し、次の正しいレコードから続ける
+pub struct Records<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Records { bytes }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record, FramError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        match Record::decode(self.bytes) {
+            Ok((record, size)) => {
+                self.bytes = &self.bytes[size..];
+                Some(Ok(record))
+            }
+            Err(e) => {
+                self.bytes = &self.bytes[resync(self.bytes)..];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// show_logでの表示
+pub struct RecordDisplay<'a> {
+    record: &'a Record,
+    strings: &'a StringTable,
+}
+
+// ログは [起動回数:起動からのms] で、現在時刻や場所があればその後に表示する
+impl fmt::Display for RecordDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let record = self.record;
+        match record.kind {
+            RecordKind::Text => write!(f, "{}", record.text()),
+            RecordKind::Log | RecordKind::Deferred => {
+                write!(f, "[{}:{:>8}] ", record.boot, record.timestamp)?;
+                if let Some(wall_time) = record.wall_time {
+                    write!(f, "{} ", WallTime(wall_time))?;
+                }
+                let level = record.level.map_or("-", |level| level.as_str());
+                write!(f, "{} ", level)?;
+                if let Some(location) = record.location {
+                    if location.module != 0 {
+                        write!(f, "{}", self.strings.name(location.module))?;
+                        write!(f, " ")?;
+                    }
+                    if location.file != 0 {
+                        write!(f, "{}", self.strings.name(location.file))?;
+                        write!(f, ":{} ", location.line)?;
+                    }
+                }
+                writeln!(
+                    f,
+                    "- {}{}",
+                    record.message(self.strings),
+                    KeyValues(&record.fields)
+                )
+            }
+            RecordKind::Boot => match record.reset_reason() {
+                Some(reason) => writeln!(f, "---- boot {} ({}) ----", record.boot, reason),
+                None => writeln!(f, "---- boot {} ----", record.boot),
+            },
+            // 文字列は表示しない
+            RecordKind::Str => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.display(&StringTable::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: u32, flags: u8) -> Record {
+        Record {
+            kind: RecordKind::Log,
+            level: Some(Level::Warn),
+            seq,
+            boot: 3,
+            timestamp: 1500,
+            wall_time: (flags & FLAG_WALL_TIME != 0).then_some(1_714_566_896_789),
+            location: (flags & FLAG_LOCATION != 0).then_some(SourceLocation {
+                file: 0x1234_5678,
+                module: 0x9ABC_DEF0,
+                line: 42,
+            }),
+            fields: if flags & FLAG_FIELDS != 0 {
+                vec![
+                    Field {
+                        key: "rpm".to_string(),
+                        value: FieldValue::Int(-1200),
+                    },
+                    Field {
+                        key: "name".to_string(),
+                        value: FieldValue::Str("left".to_string()),
+                    },
+                ]
+            } else {
+                Vec::new()
+            },
+            payload: b"spin up".to_vec(),
+        }
+    }
+
+    #[test]
+    fn round_trips_every_flag() {
+        for flags in 0..8 {
+            let record = record(7, flags);
+            let bytes = record.encode();
+            assert_eq!(bytes.len(), record.encoded_size());
+            assert_eq!(bytes[3], flags);
+
+            // 後ろに続くbyteは読まない
+            let mut longer = bytes.clone();
+            longer.extend_from_slice(&[SYNC, 0, 0]);
+            assert_eq!(Record::decode(&longer).unwrap(), (record, bytes.len()));
+        }
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        for kind in [
+            RecordKind::Text,
+            RecordKind::Boot,
+            RecordKind::Str,
+            RecordKind::Deferred,
+        ] {
+            let record = Record {
+                kind,
+                level: None,
+                ..record(0, 0)
+            };
+            let (decoded, _) = Record::decode(&record.encode()).unwrap();
+            assert_eq!(decoded, record);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_record() {
+        let bytes = record(0, 7).encode();
+        for i in 0..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x10;
+            assert!(Record::decode(&corrupt).is_err(), "byte {}", i);
+        }
+        // 途中で切れている
+        for len in 0..bytes.len() {
+            assert!(Record::decode(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn resyncs_after_corrupt_record() {
+        let records: Vec<Record> = (0..4).map(|seq| record(seq, seq as u8 * 2 + 1)).collect();
+        let mut bytes = Vec::new();
+        for record in &records {
+            bytes.extend_from_slice(&record.encode());
+        }
+        // 2つ目のレコードのペイロードを壊し、3つ目の前にゴミを入れる
+        let second = records[0].encoded_size();
+        let third = second + records[1].encoded_size();
+        bytes[third - 1] ^= 0xFF;
+        bytes.splice(third..third, [SYNC, 1, 2, SYNC]);
+
+        let read: Vec<_> = Records::new(&bytes).collect();
+        assert_eq!(read.len(), 4);
+        assert_eq!(read[0], Ok(records[0].clone()));
+        assert_eq!(read[1], Err(FramError::Corrupt));
+        assert_eq!(read[2], Ok(records[2].clone()));
+        assert_eq!(read[3], Ok(records[3].clone()));
+
+        assert_eq!(resync(&bytes[second..]), third + 4 - second);
+        assert_eq!(resync(&[SYNC, 0, SYNC]), 3);
+    }
+}
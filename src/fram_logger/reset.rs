@@ -0,0 +1,95 @@
+// リセットの原因
+//
+// 起動のレコードのペイロードに1byteで書き込む
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    Unknown,
+    PowerOn,
+    // リセットピン
+    External,
+    // esp_restartなど
+    Software,
+    Panic,
+    Watchdog,
+    Brownout,
+    DeepSleep,
+}
+
+impl ResetReason {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ResetReason::Unknown => 0,
+            ResetReason::PowerOn => 1,
+            ResetReason::External => 2,
+            ResetReason::Software => 3,
+            ResetReason::Panic => 4,
+            ResetReason::Watchdog => 5,
+            ResetReason::Brownout => 6,
+            ResetReason::DeepSleep => 7,
+        }
+    }
+
+    // 知らない値はUnknownにする
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => ResetReason::PowerOn,
+            2 => ResetReason::External,
+            3 => ResetReason::Software,
+            4 => ResetReason::Panic,
+            5 => ResetReason::Watchdog,
+            6 => ResetReason::Brownout,
+            7 => ResetReason::DeepSleep,
+            _ => ResetReason::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResetReason::Unknown => "unknown",
+            ResetReason::PowerOn => "power-on",
+            ResetReason::External => "external",
+            ResetReason::Software => "software",
+            ResetReason::Panic => "panic",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Brownout => "brownout",
+            ResetReason::DeepSleep => "deep-sleep wake",
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// リセットの原因を調べる方法
+pub trait ResetReasonSource {
+    fn reset_reason(&self) -> ResetReason;
+}
+
+// 決まった原因を返す(ホストでのテスト用)
+impl ResetReasonSource for ResetReason {
+    fn reset_reason(&self) -> ResetReason {
+        *self
+    }
+}
+
+// 実際のリセットの原因
+// ESP32ではesp_reset_reason()を使い、ホストでは常にPowerOnを返す
+pub struct SystemResetReason;
+
+impl ResetReasonSource for SystemResetReason {
+    #[cfg(target_os = "espidf")]
+    fn reset_reason(&self) -> ResetReason {
+        super::esp::reset_reason()
+    }
+
+    #[cfg(not(target_os = "espidf"))]
+    fn reset_reason(&self) -> ResetReason {
+        ResetReason::PowerOn
+    }
+}
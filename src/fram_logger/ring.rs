@@ -0,0 +1,197 @@
+// リングバッファのアドレス計算
+//
+// [start, end)の範囲をリングバッファとして使う
+// 位置は常にこの範囲に収まり、endになることはない
+
+use super::{FramDevice, FramError};
+
+#[derive(Clone, Copy)]
+pub struct Ring {
+    start: u32,
+    end: u32,
+}
+
+impl Ring {
+    pub fn new(start: u32, end: u32) -> Self {
+        assert!(start < end);
+        Ring { start, end }
+    }
+
+    pub fn size(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+
+    // posからn byte進めた位置
+    pub fn advance(&self, pos: u32, n: usize) -> u32 {
+        let offset = (pos - self.start) as usize + n % self.size();
+        self.start + (offset % self.size()) as u32
+    }
+
+    // fromからtoまでのbyte数
+    pub fn distance(&self, from: u32, to: u32) -> usize {
+        let from = (from - self.start) as usize;
+        let to = (to - self.start) as usize;
+        (to + self.size() - from) % self.size()
+    }
+
+    // posからdataを書き込む
+    // 終端をまたぐ場合は、残りを先頭から続けて書き込む
+    pub fn write(
+        &self,
+        device: &mut dyn FramDevice,
+        pos: u32,
+        data: &[u8],
+    ) -> Result<(), FramError> {
+        if data.len() > self.size() {
+            return Err(FramError::OutOfRange {
+                adrs: pos,
+                len: data.len(),
+            });
+        }
+
+        let mut pos = pos;
+        let mut data = data;
+        while !data.is_empty() {
+            let n = data.len().min((self.end - pos) as usize);
+            device.write(pos, &data[..n])?;
+            data = &data[n..];
+            pos = self.advance(pos, n);
+        }
+        Ok(())
+    }
+
+    // posからdata.len()byte読み込む
+    // 終端をまたぐ場合は、残りを先頭から続けて読み込む
+    pub fn read(
+        &self,
+        device: &mut dyn FramDevice,
+        pos: u32,
+        data: &mut [u8],
+    ) -> Result<(), FramError> {
+        if data.len() > self.size() {
+            return Err(FramError::OutOfRange {
+                adrs: pos,
+                len: data.len(),
+            });
+        }
+
+        let mut pos = pos;
+        let mut data = data;
+        while !data.is_empty() {
+            let n = data.len().min((self.end - pos) as usize);
+            let (chunk, rest) = data.split_at_mut(n);
+            device.read(pos, chunk)?;
+            data = rest;
+            pos = self.advance(pos, n);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MemFram;
+    use super::*;
+
+    const START: u32 = 0x40;
+    const END: u32 = 0x100;
+
+    fn ring() -> (Ring, MemFram) {
+        (Ring::new(START, END), MemFram::new(END as usize))
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (1..=len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn advances_across_end() {
+        let (ring, _) = ring();
+        assert_eq!(ring.advance(0x80, 0x10), 0x90);
+        assert_eq!(ring.advance(0xF0, 0x20), 0x50);
+        assert_eq!(ring.advance(0xFF, 1), START);
+        assert_eq!(ring.advance(START, ring.size()), START);
+        assert_eq!(ring.advance(0xF0, ring.size() + 0x20), 0x50);
+    }
+
+    #[test]
+    fn measures_distance_across_end() {
+        let (ring, _) = ring();
+        assert_eq!(ring.distance(0x80, 0x90), 0x10);
+        assert_eq!(ring.distance(0xF0, 0x50), 0x20);
+        assert_eq!(ring.distance(0x90, 0x80), ring.size() - 0x10);
+        assert_eq!(ring.distance(0x80, 0x80), 0);
+    }
+
+    #[test]
+    fn writes_and_reads_across_end() {
+        let (ring, mut fram) = ring();
+        let data = data(0x20);
+        ring.write(&mut fram, 0xF0, &data).unwrap();
+
+        // 終端までと、残りは先頭から
+        assert_eq!(fram.as_bytes()[0xF0..0x100], data[..0x10]);
+        assert_eq!(fram.as_bytes()[0x40..0x50], data[0x10..]);
+        assert!(fram.as_bytes()[..0x40].iter().all(|&b| b == 0));
+
+        let mut read = vec![0; data.len()];
+        ring.read(&mut fram, 0xF0, &mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn writes_up_to_end() {
+        // 終端ちょうどで終わる書き込みは、先頭に回り込まない
+        // (MemFramは容量を超える書き込みをエラーにする)
+        let (ring, mut fram) = ring();
+        let data = data(0x20);
+        ring.write(&mut fram, END - 0x20, &data).unwrap();
+        assert_eq!(fram.as_bytes()[0xE0..0x100], data[..]);
+        assert!(fram.as_bytes()[..0xE0].iter().all(|&b| b == 0));
+        assert_eq!(ring.advance(END - 0x20, data.len()), START);
+
+        let mut read = vec![0; data.len()];
+        ring.read(&mut fram, END - 0x20, &mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn writes_at_boundaries() {
+        let (ring, mut fram) = ring();
+
+        // 最後の1byte
+        ring.write(&mut fram, END - 1, &[0xAA]).unwrap();
+        assert_eq!(fram.as_bytes()[0xFF], 0xAA);
+
+        // 最後の1byteから、1byteだけ回り込む
+        ring.write(&mut fram, END - 1, &[0x11, 0x22]).unwrap();
+        assert_eq!(fram.as_bytes()[0xFF], 0x11);
+        assert_eq!(fram.as_bytes()[0x40], 0x22);
+
+        // リングバッファ全体
+        let data = data(ring.size());
+        ring.write(&mut fram, START, &data).unwrap();
+        assert_eq!(fram.as_bytes()[0x40..0x100], data[..]);
+
+        // 全体を途中から
+        ring.write(&mut fram, 0x80, &data).unwrap();
+        assert_eq!(fram.as_bytes()[0x80..0x100], data[..0x80]);
+        assert_eq!(fram.as_bytes()[0x40..0x80], data[0x80..]);
+        assert!(fram.as_bytes()[..0x40].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rejects_more_than_ring_size() {
+        let (ring, mut fram) = ring();
+        let data = data(ring.size() + 1);
+        assert!(matches!(
+            ring.write(&mut fram, START, &data),
+            Err(FramError::OutOfRange { .. })
+        ));
+        let mut read = vec![0; ring.size() + 1];
+        assert!(matches!(
+            ring.read(&mut fram, START, &mut read),
+            Err(FramError::OutOfRange { .. })
+        ));
+    }
+}
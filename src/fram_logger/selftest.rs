@@ -0,0 +1,191 @@
+// 起動時のFRAMの自己診断
+
+use super::device_id::DeviceId;
+use super::header::SCRATCH_ADRS;
+use super::{FramDevice, FramError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfTest {
+    // FRAMがACKを返したか
+    pub present: bool,
+
+    // Device ID(読まなかった場合や、対応していない型番ではNone)
+    pub device_id: Option<DeviceId>,
+
+    // スクラッチ領域に書き込んだ値を読み出せたか
+    pub readback: bool,
+
+    // 最初に起きたエラー
+    pub error: Option<FramError>,
+}
+
+impl SelfTest {
+    pub fn is_ok(&self) -> bool {
+        self.present && self.readback
+    }
+
+    pub fn run(device: &mut dyn FramDevice, read_id: bool) -> SelfTest {
+        let mut result = SelfTest {
+            present: false,
+            device_id: None,
+            readback: false,
+            error: None,
+        };
+
+        if let Err(e) = device.probe() {
+            result.error = Some(e);
+            return result;
+        }
+        result.present = true;
+
+        if read_id {
+            match device.device_id() {
+                Ok(id) => result.device_id = id,
+                Err(e) => result.error = Some(e),
+            }
+        }
+
+        match readback(device) {
+            Ok(readback) => result.readback = readback,
+            Err(e) => {
+                result.error.get_or_insert(e);
+            }
+        }
+        result
+    }
+}
+
+// スクラッチ領域の値を反転して書き込み、読み出して比べてから元に戻す
+fn readback(device: &mut dyn FramDevice) -> Result<bool, FramError> {
+    let mut original: [u8; 1] = [0];
+    device.read(SCRATCH_ADRS, &mut original)?;
+
+    let pattern = [!original[0]];
+    let mut data: [u8; 1] = [0];
+    device.write(SCRATCH_ADRS, &pattern)?;
+    let read = device.read(SCRATCH_ADRS, &mut data);
+
+    // 読み込みに失敗しても元に戻す
+    device.write(SCRATCH_ADRS, &original)?;
+    read?;
+
+    Ok(data == pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fram_logger::MemFram;
+
+    // 故障を再現するFRAM
+    struct FaultyFram {
+        memory: MemFram,
+        probe: Result<(), FramError>,
+        device_id: Result<Option<DeviceId>, FramError>,
+        // 書き込みを無視する(書き込めない故障)
+        read_only: bool,
+    }
+
+    impl FaultyFram {
+        fn new() -> Self {
+            let mut memory = MemFram::new(0x200);
+            memory.write(SCRATCH_ADRS, &[0x5A]).unwrap();
+            FaultyFram {
+                memory,
+                probe: Ok(()),
+                device_id: Ok(None),
+                read_only: false,
+            }
+        }
+
+        fn scratch(&self) -> u8 {
+            self.memory.as_bytes()[SCRATCH_ADRS as usize]
+        }
+    }
+
+    impl FramDevice for FaultyFram {
+        fn capacity(&self) -> usize {
+            self.memory.capacity()
+        }
+
+        fn read(&mut self, adrs: u32, data: &mut [u8]) -> Result<(), FramError> {
+            self.memory.read(adrs, data)
+        }
+
+        fn write(&mut self, adrs: u32, data: &[u8]) -> Result<(), FramError> {
+            if self.read_only {
+                return Ok(());
+            }
+            self.memory.write(adrs, data)
+        }
+
+        fn probe(&mut self) -> Result<(), FramError> {
+            self.probe
+        }
+
+        fn device_id(&mut self) -> Result<Option<DeviceId>, FramError> {
+            self.device_id
+        }
+    }
+
+    #[test]
+    fn passes_on_mem_fram() {
+        let mut fram = MemFram::new(0x200);
+        fram.write(SCRATCH_ADRS, &[0xC3]).unwrap();
+        let result = SelfTest::run(&mut fram, true);
+        assert!(result.is_ok());
+        assert_eq!(result.device_id, None);
+        assert_eq!(result.error, None);
+        // スクラッチ領域は元に戻す
+        assert_eq!(fram.as_bytes()[SCRATCH_ADRS as usize], 0xC3);
+    }
+
+    #[test]
+    fn reports_missing_fram() {
+        let mut fram = FaultyFram::new();
+        fram.probe = Err(FramError::Nack);
+        let result = SelfTest::run(&mut fram, true);
+        assert!(!result.is_ok());
+        assert!(!result.present);
+        assert!(!result.readback);
+        assert_eq!(result.error, Some(FramError::Nack));
+        assert_eq!(fram.scratch(), 0x5A);
+    }
+
+    #[test]
+    fn reports_readback_mismatch() {
+        let mut fram = FaultyFram::new();
+        fram.read_only = true;
+        let result = SelfTest::run(&mut fram, false);
+        assert!(!result.is_ok());
+        assert!(result.present);
+        assert!(!result.readback);
+        assert_eq!(result.error, None);
+        assert_eq!(fram.scratch(), 0x5A);
+    }
+
+    #[test]
+    fn reports_device_id() {
+        let id = DeviceId::from_bytes([0x00, 0xA5, 0x10]);
+        let mut fram = FaultyFram::new();
+        fram.device_id = Ok(Some(id));
+        let result = SelfTest::run(&mut fram, true);
+        assert!(result.is_ok());
+        assert_eq!(result.device_id, Some(id));
+
+        // 読まない場合
+        assert_eq!(SelfTest::run(&mut fram, false).device_id, None);
+    }
+
+    #[test]
+    fn continues_after_device_id_error() {
+        // Device IDを読めなくても、読み書きは確かめる
+        let mut fram = FaultyFram::new();
+        fram.device_id = Err(FramError::Timeout);
+        let result = SelfTest::run(&mut fram, true);
+        assert!(result.is_ok());
+        assert_eq!(result.device_id, None);
+        assert_eq!(result.error, Some(FramError::Timeout));
+        assert_eq!(fram.scratch(), 0x5A);
+    }
+}
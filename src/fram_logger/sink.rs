@@ -0,0 +1,335 @@
+// ログの出力先
+//
+// FramLoggerは、登録された出力先のうちフィルタを通ったものすべてに書き込む
+//
+// let logger = FramLogger::new()
+//     .sink("warn".parse()?, FramSink::new(Format::Message))
+//     .sink("debug".parse()?, ConsoleSink::new(Format::Level).location(LocationDetail::File));
+
+use super::fields::{self, Field, KeyValues};
+use super::fram_log;
+use log::Level;
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// 出力するログ1件
+pub struct Entry<'a> {
+    pub level: Level,
+    pub target: &'a str,
+    pub args: fmt::Arguments<'a>,
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+    pub module_path: Option<&'a str>,
+    pub fields: Vec<Field>,
+}
+
+impl<'a> Entry<'a> {
+    pub fn from_record(record: &log::Record<'a>) -> Self {
+        Entry {
+            level: record.level(),
+            target: record.target(),
+            args: *record.args(),
+            file: record.file(),
+            line: record.line(),
+            module_path: record.module_path(),
+            fields: fields::collect(record.key_values()),
+        }
+    }
+
+    pub fn metadata(&self) -> log::Metadata<'a> {
+        log::Metadata::builder()
+            .level(self.level)
+            .target(self.target)
+            .build()
+    }
+}
+
+// ログの書式
+#[derive(Clone, Copy)]
+pub enum Format {
+    // メッセージのみ
+    Message,
+    // "INFO - メッセージ"
+    Level,
+    // "INFO target - メッセージ"
+    Target,
+    // 任意の書式
+    Custom(fn(&mut dyn fmt::Write, &Entry) -> fmt::Result),
+}
+
+impl Format {
+    pub fn write(&self, w: &mut dyn fmt::Write, entry: &Entry) -> fmt::Result {
+        match self {
+            Format::Message => write!(w, "{}", entry.args),
+            Format::Level => write!(w, "{} - {}", entry.level, entry.args),
+            Format::Target => write!(w, "{} {} - {}", entry.level, entry.target, entry.args),
+            Format::Custom(f) => f(w, entry),
+        }
+    }
+
+    pub fn render(&self, entry: &Entry) -> String {
+        let mut s = String::new();
+        let _ = self.write(&mut s, entry);
+        s
+    }
+}
+
+// ログを書き込んだ場所をどこまで出力するか
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocationDetail {
+    // 出力しない
+    None,
+    // ファイル名と行番号
+    File,
+    // モジュール名、ファイル名と行番号
+    Full,
+}
+
+impl LocationDetail {
+    fn file<'a>(&self, entry: &Entry<'a>) -> Option<&'a str> {
+        match self {
+            LocationDetail::None => None,
+            _ => entry.file,
+        }
+    }
+
+    fn module_path<'a>(&self, entry: &Entry<'a>) -> Option<&'a str> {
+        match self {
+            LocationDetail::Full => entry.module_path,
+            _ => None,
+        }
+    }
+
+    // "[モジュール名 ファイル名:行番号] "
+    fn write(&self, w: &mut dyn fmt::Write, entry: &Entry) -> fmt::Result {
+        let (file, module_path) = (self.file(entry), self.module_path(entry));
+        if file.is_none() && module_path.is_none() {
+            return Ok(());
+        }
+        write!(w, "[")?;
+        if let Some(module_path) = module_path {
+            write!(w, "{}", module_path)?;
+            if file.is_some() {
+                write!(w, " ")?;
+            }
+        }
+        if let Some(file) = file {
+            write!(w, "{}:{}", file, entry.line.unwrap_or(0))?;
+        }
+        write!(w, "] ")
+    }
+
+    // 場所とkey-valueを付けて文字列にする
+    fn render(&self, format: &Format, entry: &Entry) -> String {
+        let mut s = String::new();
+        let _ = self.write(&mut s, entry);
+        let _ = format.write(&mut s, entry);
+        let _ = write!(s, "{}", KeyValues(&entry.fields));
+        s
+    }
+}
+
+// 出力先
+pub trait Sink: Send + Sync {
+    fn write(&self, entry: &Entry);
+
+    // panicハンドラから書き込む
+    // ロックを持ったままpanicしている場合があるので、待ち続けないようにする
+    fn write_panic(&self, entry: &Entry) {
+        self.write(entry);
+    }
+
+    // FRAMに書き込むか(flog!をどのフィルタで判定するかに使う)
+    fn writes_fram(&self) -> bool {
+        false
+    }
+}
+
+// FRAM(fram_logger::init後に書き込まれる)
+// レベルはレコードに保存されるので、Format::Messageで十分
+// 場所はテキストではなく、ハッシュにしてレコードに保存する(既定ではすべて)
+// key-valueも、型を残したままレコードに保存する
+pub struct FramSink {
+    format: Format,
+    location: LocationDetail,
+}
+
+impl FramSink {
+    pub fn new(format: Format) -> Self {
+        FramSink {
+            format,
+            location: LocationDetail::Full,
+        }
+    }
+
+    pub fn location(mut self, location: LocationDetail) -> Self {
+        self.location = location;
+        self
+    }
+}
+
+impl Sink for FramSink {
+    fn write(&self, entry: &Entry) {
+        if let Ok(log) = fram_log() {
+            let s = self.format.render(entry);
+            let _ = log.log_at(
+                entry.level,
+                self.location.file(entry),
+                entry.line,
+                self.location.module_path(entry),
+                &entry.fields,
+                format_args!("{}", s),
+            );
+        }
+    }
+
+    fn write_panic(&self, entry: &Entry) {
+        if let Ok(log) = fram_log() {
+            let s = self.format.render(entry);
+            let _ = log.try_log_at(
+                entry.level,
+                self.location.file(entry),
+                entry.line,
+                self.location.module_path(entry),
+                &entry.fields,
+                format_args!("{}", s),
+            );
+        }
+    }
+
+    fn writes_fram(&self) -> bool {
+        true
+    }
+}
+
+// シリアル(標準出力)
+// 場所は既定では出力しない
+pub struct ConsoleSink {
+    format: Format,
+    location: LocationDetail,
+}
+
+impl ConsoleSink {
+    pub fn new(format: Format) -> Self {
+        ConsoleSink {
+            format,
+            location: LocationDetail::None,
+        }
+    }
+
+    pub fn location(mut self, location: LocationDetail) -> Self {
+        self.location = location;
+        self
+    }
+}
+
+impl Sink for ConsoleSink {
+    fn write(&self, entry: &Entry) {
+        println!("{}", self.location.render(&self.format, entry));
+    }
+}
+
+// メモリ上に溜める(ホストでのテスト用)
+// cloneしたものは同じ内容を共有するので、ロガーに渡した後も読み出せる
+#[derive(Clone)]
+pub struct MemorySink {
+    format: Format,
+    location: LocationDetail,
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl MemorySink {
+    pub fn new(format: Format) -> Self {
+        MemorySink {
+            format,
+            location: LocationDetail::None,
+            lines: Arc::default(),
+        }
+    }
+
+    pub fn location(mut self, location: LocationDetail) -> Self {
+        self.location = location;
+        self
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lock().clone()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<String>> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Sink for MemorySink {
+    fn write(&self, entry: &Entry) {
+        let line = self.location.render(&self.format, entry);
+        self.lock().push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Filter, FramLogger};
+    use super::*;
+    use log::kv::Value;
+    use log::Log;
+    use std::panic;
+
+    #[test]
+    fn writes_to_each_enabled_sink() {
+        let warn = MemorySink::new(Format::Level);
+        let debug = MemorySink::new(Format::Target).location(LocationDetail::Full);
+        let logger = FramLogger::new()
+            .sink("warn".parse().unwrap(), warn.clone())
+            .sink("debug,app::noisy=off".parse().unwrap(), debug.clone());
+
+        let kvs = [("id", Value::from(7))];
+        let cases = [
+            (Level::Error, "app", "error", &kvs[..]),
+            (Level::Info, "app::motor", "info", &[][..]),
+            (Level::Warn, "app::noisy", "warn", &[][..]),
+            (Level::Trace, "app", "trace", &[][..]),
+        ];
+        for (level, target, message, kvs) in cases {
+            logger.log(
+                &log::Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{}", message))
+                    .file(Some("src/app.rs"))
+                    .line(Some(12))
+                    .module_path(Some(target))
+                    .key_values(&kvs)
+                    .build(),
+            );
+        }
+
+        assert_eq!(warn.lines(), ["ERROR - error id=7", "WARN - warn"]);
+        assert_eq!(
+            debug.lines(),
+            [
+                "[app src/app.rs:12] ERROR app - error id=7",
+                "[app::motor src/app.rs:12] INFO app::motor - info",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_panic_to_sinks() {
+        let sink = MemorySink::new(Format::Level).location(LocationDetail::File);
+        let logger = FramLogger::new().sink(Filter::default(), sink.clone());
+        logger.write_panic(Some(panic::Location::caller()), format_args!("boom"));
+        logger.write_panic(None, format_args!("no location"));
+
+        let lines = sink.lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with('[') && lines[0].contains("sink.rs:"));
+        assert!(lines[0].ends_with("] ERROR - boom"));
+        assert_eq!(lines[1], "ERROR - no location");
+    }
+}
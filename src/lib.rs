@@ -0,0 +1,3 @@
+#[macro_use] // マクロを使うためのおまじない
+pub mod fram_logger;
+pub use crate::fram_logger::fram_print;
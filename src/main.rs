@@ -1,26 +1,34 @@
-use esp_idf_hal::peripherals::Peripherals;
-
-#[macro_use] // マクロを使うためのおまじない
-pub mod fram_logger;
-use crate::fram_logger::fram_print;
+use fram::fram_logger::{self, FramLog, FramPart};
 
 fn main() {
-    esp_idf_svc::sys::link_patches();
-
     // FRAMとpanicハンドラの初期化
-    let mut peripherals = Peripherals::take().unwrap();
-    let _ = fram_logger::init(&mut peripherals);
-    let _ = fram_logger::set_panic_handler();
+    // FRAMが使えなくても、ログはシリアルに出力される
+    let fram_log = init();
+    fram_logger::set_panic_handler();
     let _ = fram_logger::set_log(log::LevelFilter::Info);
 
-    // 前回のログを表示
-    fram_logger::show_log();
+    match fram_log {
+        Ok(fram_log) => {
+            // FRAMの自己診断
+            let result = fram_log.self_test(true);
+            if !result.is_ok() {
+                log::error!("FRAM self test failed: {:?}", result);
+            }
+
+            // 前回までのログを表示
+            let _ = fram_log.show_log();
+        }
+        Err(e) => log::error!("FRAM is not available: {}", e),
+    }
 
     // ログを書き込む
     log::info!("FRAM logger test");
     log::info!("Info test");
     log::error!("Error test");
 
+    // 書式化を後回しにしてFRAMに書き込む(シリアルには出力されない)
+    fram::flog!(log::Level::Info, "Deferred test: {} {:.1}", 42, 1.5);
+
     // 意図的なpanic
     let array: [u8; 3] = [1, 2, 3];
 
@@ -28,3 +36,29 @@ fn main() {
         log::info!("array[{}] = {}", i, array[i]); // i = 3でpanic
     }
 }
+
+#[cfg(target_os = "espidf")]
+fn init() -> anyhow::Result<&'static FramLog> {
+    use esp_idf_hal::peripherals::Peripherals;
+
+    esp_idf_svc::sys::link_patches();
+
+    let peripherals = Peripherals::take().unwrap();
+    let config = fram_logger::FramConfig::new()
+        .part(FramPart::MB85RC64)
+        .detect_part(true);
+    let (fram_log, _i2c) = fram_logger::init(
+        peripherals.i2c0,
+        peripherals.pins.gpio18,
+        peripherals.pins.gpio17,
+        &config,
+    )?;
+    Ok(fram_log)
+}
+
+// ホストではメモリ上のFRAMで動かす
+#[cfg(not(target_os = "espidf"))]
+fn init() -> anyhow::Result<&'static FramLog> {
+    let capacity = FramPart::MB85RC64.capacity as usize;
+    Ok(fram_logger::init_with(fram_logger::MemFram::new(capacity))?)
+}
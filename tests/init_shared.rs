@@ -0,0 +1,93 @@
+// init_sharedのテスト
+//
+// FRAM(MB85RC256)を模したI2Cバスを、テスト側と共有して渡す
+// FRAM_LOGはプロセスで1つなので、このファイルは1つのテストだけにする
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+use fram::fram_logger::{fram_log, init_shared, FramConfig, RecordKind, ResetReason};
+use std::sync::{Arc, Mutex};
+
+const FRAM_ADDRESS: u8 = 0x50;
+const DEVICE_ID_ADDRESS: u8 = 0x7C;
+const CAPACITY: usize = 0x8000;
+
+// Fujitsu、256Kbit
+const DEVICE_ID: [u8; 3] = [0x00, 0xA5, 0x10];
+
+struct Bus {
+    memory: Vec<u8>,
+    device_id_reads: usize,
+}
+
+// 同じバスを共有するハンドル
+#[derive(Clone)]
+struct SharedBus(Arc<Mutex<Bus>>);
+
+impl ErrorType for SharedBus {
+    type Error = ErrorKind;
+}
+
+impl I2c for SharedBus {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let mut bus = self.0.lock().unwrap();
+        match (address, operations) {
+            (DEVICE_ID_ADDRESS, [Operation::Write(&[slave]), Operation::Read(id)])
+                if slave == FRAM_ADDRESS << 1 =>
+            {
+                bus.device_id_reads += 1;
+                id.copy_from_slice(&DEVICE_ID);
+                Ok(())
+            }
+            (FRAM_ADDRESS, [Operation::Write(bytes)]) => {
+                let adrs = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+                let data = &bytes[2..];
+                bus.memory[adrs..adrs + data.len()].copy_from_slice(data);
+                Ok(())
+            }
+            (FRAM_ADDRESS, [Operation::Write(&[high, low]), Operation::Read(data)]) => {
+                let adrs = u16::from_be_bytes([high, low]) as usize;
+                data.copy_from_slice(&bus.memory[adrs..adrs + data.len()]);
+                Ok(())
+            }
+            _ => Err(nack),
+        }
+    }
+}
+
+#[test]
+fn initializes_on_shared_bus() {
+    let bus = SharedBus(Arc::new(Mutex::new(Bus {
+        memory: vec![0; CAPACITY],
+        device_id_reads: 0,
+    })));
+
+    // 既定のMB85RC64より大きいことをDevice IDから調べる
+    let config = FramConfig::new().detect_part(true);
+    let log = init_shared(bus.clone(), &config).unwrap();
+    assert!(std::ptr::eq(log, fram_log().unwrap()));
+    assert_eq!(bus.0.lock().unwrap().device_id_reads, 1);
+    assert_eq!(&bus.0.lock().unwrap().memory[0..4], b"FLOG");
+
+    let records = log.records().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].kind, RecordKind::Boot);
+    assert_eq!(records[0].reset_reason(), Some(ResetReason::PowerOn));
+
+    // MB85RC64の容量を超えて書いても、最初のレコードは残る
+    for i in 0..300 {
+        log.print(format_args!("message {:04} on the shared bus", i))
+            .unwrap();
+    }
+    let records = log.records().unwrap();
+    assert_eq!(records.len(), 301);
+    assert_eq!(records[0].kind, RecordKind::Boot);
+    assert_eq!(records[300].text(), "message 0299 on the shared bus");
+    assert!(bus.0.lock().unwrap().memory[0x2000..]
+        .iter()
+        .any(|&b| b != 0));
+}
@@ -0,0 +1,42 @@
+// init_withのテスト
+//
+// FRAM_LOGはプロセスで1つなので、このファイルは1つのテストだけにする
+
+use fram::fram_logger::{fram_log, init_with, FramError, FramLog, MemFram, RecordKind};
+
+#[test]
+fn installs_only_after_boot_record() {
+    // フォーマット済みのFRAM
+    let image = {
+        let log = FramLog::new(MemFram::new(0x2000)).unwrap();
+        let mut image = vec![0; 0x2000];
+        log.read(0, &mut image).unwrap();
+        image
+    };
+
+    // 開くときのヘッダの書き込みだけで電源が切れるbyte数
+    let limit = (0..)
+        .find(|&limit| {
+            let mut fram = MemFram::from_bytes(image.clone());
+            fram.cut_power_after(limit);
+            FramLog::new(fram).is_ok()
+        })
+        .unwrap();
+
+    // 起動のレコードを書き込めなければ、登録しない
+    let mut fram = MemFram::from_bytes(image.clone());
+    fram.cut_power_after(limit);
+    assert_eq!(init_with(fram).err(), Some(FramError::Nack));
+    assert_eq!(fram_log().err(), Some(FramError::NotInitialized));
+
+    // 登録していないので、もう一度初期化できる
+    let log = init_with(MemFram::from_bytes(image)).unwrap();
+    assert!(std::ptr::eq(log, fram_log().unwrap()));
+    let records = log.records().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].kind, RecordKind::Boot);
+    assert_eq!(
+        init_with(MemFram::new(0x2000)).err(),
+        Some(FramError::AlreadyInitialized)
+    );
+}